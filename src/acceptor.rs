@@ -0,0 +1,114 @@
+use crate::{
+    config::Config,
+    server::{Limits, Stop},
+};
+use async_std::{io, net::TcpListener};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+// Binds one SO_REUSEPORT listener per acceptor, each steered by SO_INCOMING_CPU to the
+// core its thread is pinned to. Every acceptor runs on its own thread and executor, so only
+// those threads are pinned and the caller's affinity, inherited by whatever threads it starts
+// later, is left alone. Returns the address they all listen on and the threads to join.
+pub(crate) fn spawn(
+    config: &Arc<Config>,
+    limits: &Arc<Limits>,
+    stop: &Stop,
+) -> io::Result<(SocketAddr, Vec<JoinHandle<io::Result<()>>>)> {
+    let addr = config
+        .bind_addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to bind"))?;
+    let cpus = online_cpus();
+
+    let mut listeners = vec![bind_reuseport(addr, 0)?];
+    // Binding port 0 has to land every acceptor on the port the first one got
+    let addr = listeners[0].local_addr()?;
+    for i in 1..config.acceptors {
+        listeners.push(bind_reuseport(addr, i % cpus)?);
+    }
+
+    let mut threads = vec![];
+    for (i, listener) in listeners.into_iter().enumerate() {
+        let cpu = i % cpus;
+        let config = config.clone();
+        let limits = limits.clone();
+        let stop = stop.clone();
+
+        let thread = std::thread::Builder::new()
+            .name(format!("acceptor-{}", i))
+            .spawn(move || {
+                let _ = pin_to_cpu(cpu);
+                let self_addrs = crate::server::self_addrs(addr, &config);
+                futures::executor::block_on(crate::server::serve_tcp(
+                    TcpListener::from(listener),
+                    &config,
+                    &limits,
+                    &self_addrs,
+                    stop,
+                ))
+            })?;
+        threads.push(thread);
+    }
+
+    Ok((addr, threads))
+}
+
+fn bind_reuseport(addr: SocketAddr, cpu: usize) -> io::Result<std::net::TcpListener> {
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    set_incoming_cpu(&socket, cpu)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(socket.into_tcp_listener())
+}
+
+fn set_incoming_cpu(socket: &Socket, cpu: usize) -> io::Result<()> {
+    let cpu = cpu as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_INCOMING_CPU,
+            &cpu as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn pin_to_cpu(cpu: usize) -> io::Result<()> {
+    let ret = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+fn online_cpus() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) } {
+        n if n > 0 => n as usize,
+        _ => 1,
+    }
+}
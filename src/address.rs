@@ -0,0 +1,43 @@
+use std::net::{IpAddr, SocketAddr};
+
+// A destination as the client named it: an address, or a domain yet to be resolved
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Address {
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::Ip(addr) => addr.port(),
+            Address::Domain(_, port) => *port,
+        }
+    }
+
+    pub fn domain(&self) -> Option<&str> {
+        match self {
+            Address::Ip(_) => None,
+            Address::Domain(domain, _) => Some(domain),
+        }
+    }
+
+    pub(crate) fn from_ip(ip: IpAddr, port: u16) -> Self {
+        Address::Ip(SocketAddr::new(ip, port))
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Address::Ip(addr)
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Address::Ip(addr) => write!(f, "{}", addr),
+            Address::Domain(domain, port) => write!(f, "{}:{}", domain, port),
+        }
+    }
+}
@@ -0,0 +1,95 @@
+use futures::{
+    future::{self, BoxFuture, FutureExt},
+    io::{AsyncRead, AsyncWrite},
+};
+use std::io;
+use std::sync::RwLock;
+
+pub enum AuthResult {
+    Success,
+    Failure,
+}
+
+// Decides who gets to use the proxy. Implement it to check credentials against
+// your own user store and hand it to `Builder::authenticator`.
+pub trait Authenticator: Send + Sync {
+    // Whether clients have to send a username and password (RFC 1929) at all
+    fn wants_user_pass(&self) -> bool;
+
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a [u8],
+    ) -> BoxFuture<'a, AuthResult>;
+}
+
+// Lets everyone in without asking
+pub struct NoAuth;
+
+impl Authenticator for NoAuth {
+    fn wants_user_pass(&self) -> bool {
+        false
+    }
+
+    fn authenticate<'a>(&'a self, _: &'a str, _: &'a [u8]) -> BoxFuture<'a, AuthResult> {
+        future::ready(AuthResult::Success).boxed()
+    }
+}
+
+// A list of username/password pairs, as given with `--user` or in `--users-file`
+pub struct StaticUserPass {
+    users: RwLock<Vec<(String, String)>>,
+}
+
+impl StaticUserPass {
+    pub fn new(users: Vec<(String, String)>) -> Self {
+        StaticUserPass {
+            users: RwLock::new(users),
+        }
+    }
+
+    // Swaps in a reloaded list whole
+    pub(crate) fn replace(&self, users: Vec<(String, String)>) {
+        *self.users.write().unwrap_or_else(|err| err.into_inner()) = users;
+    }
+}
+
+impl Authenticator for StaticUserPass {
+    fn wants_user_pass(&self) -> bool {
+        true
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        username: &'a str,
+        password: &'a [u8],
+    ) -> BoxFuture<'a, AuthResult> {
+        let users = self.users.read().unwrap_or_else(|err| err.into_inner());
+        let valid = users.iter().any(|(user, pass)| {
+            user == username && crate::ioutil::constant_time_eq(pass.as_bytes(), password)
+        });
+        future::ready(if valid {
+            AuthResult::Success
+        } else {
+            AuthResult::Failure
+        })
+        .boxed()
+    }
+}
+
+// The client connection as a private auth method gets to see it
+pub trait AuthStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AuthStream for T {}
+
+// A private auth method (0x80-0xFE) with a sub-negotiation of its own, for client agents
+// that carry more than a username and password. Register it with `Builder::auth_method`;
+// a client offering it gets it ahead of the standard methods.
+pub trait AuthMethod: Send + Sync {
+    // Runs the sub-negotiation once the method's been chosen, returning who the client is,
+    // or None to turn it away. It mustn't read past its own messages, the request follows.
+    fn negotiate<'a>(
+        &'a self,
+        stream: &'a mut dyn AuthStream,
+    ) -> BoxFuture<'a, io::Result<Option<String>>>;
+}
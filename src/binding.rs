@@ -0,0 +1,45 @@
+use crate::config::Config;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Users bound at once; past this, expired bindings are dropped before adding more
+const MAX_ENTRIES: usize = 65536;
+
+// Who a server's users are bound to, and when they last authenticated
+#[derive(Default)]
+pub(crate) struct Bindings {
+    users: Mutex<HashMap<String, (IpAddr, Instant)>>,
+}
+
+// With `--user-binding`, a user is tied to the address it last authenticated from until
+// that long has passed without it authenticating again; other addresses are turned away
+// meanwhile. Returns whether `user` may go on from `ip`.
+pub(crate) fn check(user: &str, ip: IpAddr, config: &Config) -> bool {
+    let period = match config.user_binding {
+        Some(period) => period,
+        None => return true,
+    };
+
+    let mut bindings = config
+        .state
+        .bindings
+        .users
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    if let Some((bound, last)) = bindings.get_mut(user) {
+        if *bound != ip && last.elapsed() < period {
+            return false;
+        }
+        *bound = ip;
+        *last = Instant::now();
+        return true;
+    }
+
+    if bindings.len() >= MAX_ENTRIES {
+        bindings.retain(|_, (_, last)| last.elapsed() < period);
+    }
+    bindings.insert(user.to_string(), (ip, Instant::now()));
+    true
+}
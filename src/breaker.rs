@@ -0,0 +1,158 @@
+use crate::config::Config;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Destinations tracked at once; only failing ones are kept
+const MAX_ENTRIES: usize = 4096;
+
+pub(crate) const DEFAULT_COOLDOWN: Duration = Duration::from_secs(30);
+
+// A server's failing destinations
+#[derive(Default)]
+pub(crate) struct Breakers {
+    circuits: Mutex<HashMap<SocketAddr, Circuit>>,
+}
+
+#[derive(Default)]
+struct Circuit {
+    failures: usize,
+    // When the circuit opened or last let a probe through
+    opened: Option<Instant>,
+}
+
+// Fails fast for a destination whose last `--circuit-breaker` connects all failed.
+// Every `--circuit-cooldown` one connect is let through as a probe, and a success
+// closes the circuit again.
+pub(crate) fn check(addr: SocketAddr, config: &Config) -> io::Result<()> {
+    let threshold = match config.circuit_breaker {
+        Some(threshold) => threshold,
+        None => return Ok(()),
+    };
+
+    let mut circuits = config
+        .state
+        .breakers
+        .circuits
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    let circuit = match circuits.get_mut(&addr) {
+        Some(circuit) if circuit.failures >= threshold => circuit,
+        _ => return Ok(()),
+    };
+
+    let cooled_down = circuit
+        .opened
+        .is_some_and(|opened| opened.elapsed() >= config.circuit_cooldown);
+    if cooled_down {
+        circuit.opened = Some(Instant::now());
+        return Ok(());
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::HostUnreachable,
+        format!("circuit open for {}", addr),
+    ))
+}
+
+pub(crate) fn record(addr: SocketAddr, ok: bool, config: &Config) {
+    let threshold = match config.circuit_breaker {
+        Some(threshold) => threshold,
+        None => return,
+    };
+
+    let mut circuits = config
+        .state
+        .breakers
+        .circuits
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    if ok {
+        circuits.remove(&addr);
+        return;
+    }
+
+    if !circuits.contains_key(&addr) && circuits.len() >= MAX_ENTRIES {
+        return;
+    }
+    let circuit = circuits.entry(addr).or_default();
+    circuit.failures += 1;
+    if circuit.failures == threshold {
+        circuit.opened = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(threshold: usize, cooldown: Duration) -> Config {
+        Config {
+            circuit_breaker: Some(threshold),
+            circuit_cooldown: cooldown,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn opens_at_the_threshold() {
+        let config = config(3, Duration::from_secs(60));
+        let (addr, other) = (
+            "192.0.2.1:80".parse().unwrap(),
+            "192.0.2.2:80".parse().unwrap(),
+        );
+
+        for _ in 0..2 {
+            record(addr, false, &config);
+            assert!(check(addr, &config).is_ok());
+        }
+        record(addr, false, &config);
+        let err = check(addr, &config).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::HostUnreachable);
+        assert!(check(other, &config).is_ok());
+
+        // A success before the threshold starts the count over
+        record(other, false, &config);
+        record(other, false, &config);
+        record(other, true, &config);
+        record(other, false, &config);
+        assert!(check(other, &config).is_ok());
+    }
+
+    #[test]
+    fn probes_after_the_cooldown() {
+        let config = config(1, Duration::from_millis(50));
+        let addr = "192.0.2.1:80".parse().unwrap();
+
+        record(addr, false, &config);
+        assert!(check(addr, &config).is_err());
+        std::thread::sleep(Duration::from_millis(60));
+
+        // One probe is let through, the rest still fail fast while it's out
+        assert!(check(addr, &config).is_ok());
+        assert!(check(addr, &config).is_err());
+
+        // A failed probe keeps the circuit open for another cooldown
+        record(addr, false, &config);
+        assert!(check(addr, &config).is_err());
+        std::thread::sleep(Duration::from_millis(60));
+
+        // A successful one closes it
+        assert!(check(addr, &config).is_ok());
+        record(addr, true, &config);
+        assert!(check(addr, &config).is_ok());
+        assert!(check(addr, &config).is_ok());
+    }
+
+    #[test]
+    fn off_without_a_threshold() {
+        let config = Config::default();
+        let addr = "192.0.2.1:80".parse().unwrap();
+        for _ in 0..10 {
+            record(addr, false, &config);
+        }
+        assert!(check(addr, &config).is_ok());
+    }
+}
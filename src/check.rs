@@ -0,0 +1,143 @@
+use crate::{
+    address::Address,
+    config::Config,
+    errors::{ConnContext, Socks5Error},
+    server::Decision,
+};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+
+// `check-config [--test 'dst=HOST:PORT']... <server options>`: validates the options the
+// server would start with, then reports how each hypothetical request would be decided
+pub fn run(args: impl Iterator<Item = String>) -> i32 {
+    let mut tests = vec![];
+    let mut rest = vec![];
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        if arg == "--test" {
+            match args.next() {
+                Some(test) => tests.push(test),
+                None => {
+                    eprintln!("[Err] missing value for --test");
+                    return 2;
+                }
+            }
+        } else {
+            rest.push(arg);
+        }
+    }
+
+    let config = match Config::from_args(rest.into_iter()) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+    println!("config ok");
+
+    let mut status = 0;
+    for test in &tests {
+        if let Err(err) = check(&config, test) {
+            eprintln!("{}: {}", test, err);
+            status = 1;
+        }
+    }
+    status
+}
+
+fn check(config: &Config, test: &str) -> Result<(), Socks5Error> {
+    let mut dst = None;
+    for pair in test.split_whitespace() {
+        match pair.split_once('=') {
+            Some(("dst", value)) => dst = Some(value),
+            // Nothing decides differently by user, so a test giving one would mislead
+            _ => {
+                return Err(Socks5Error::InvalidConfig(format!(
+                    "unsupported test field {}",
+                    pair
+                )))
+            }
+        }
+    }
+    let dst = dst.ok_or_else(|| Socks5Error::InvalidConfig("missing dst=".to_string()))?;
+    let target = parse_target(dst)?;
+
+    // Decided exactly as a CONNECT would be, by the server's own code
+    let mut ctx = ConnContext::new(0, "check-config".to_string());
+    let self_addrs = config
+        .bind_addr
+        .to_socket_addrs()?
+        .flat_map(|addr| crate::server::self_addrs(addr, config))
+        .collect::<Vec<_>>();
+    let decision = futures::executor::block_on(crate::server::decide(
+        &target,
+        &mut ctx,
+        config,
+        &self_addrs,
+    ));
+
+    match decision {
+        Decision::Connect(addrs) if addrs.is_empty() => {
+            println!("{}: accept, resolved by upstream {}", dst, upstream(config));
+        }
+        Decision::Connect(addrs) => {
+            for addr in addrs {
+                let rule = match &config.exit_policy {
+                    None => "accept (no exit policy)".to_string(),
+                    Some(policy) => match policy.matching_rule(&addr) {
+                        Some(rule) => rule.to_string(),
+                        None => "accept (no rule matched)".to_string(),
+                    },
+                };
+                let bind = config
+                    .exit_policy
+                    .as_ref()
+                    .and_then(|policy| policy.bind_for(&addr))
+                    .or(config.outbound_bind);
+                let route = match bind {
+                    _ if config.upstream.is_some() => {
+                        format!(", via upstream {}", upstream(config))
+                    }
+                    Some(bind) => format!(", from {}", bind),
+                    None => String::new(),
+                };
+                println!("{} [{}]: {}{}", dst, addr, rule, route);
+            }
+        }
+        Decision::Deny(_) => {
+            // A domain turned away on its port alone was never looked up
+            let rule = ctx
+                .resolved
+                .first()
+                .map(|ip| SocketAddr::new(*ip, target.port()))
+                .and_then(|addr| config.exit_policy.as_ref()?.matching_rule(&addr));
+            match rule {
+                Some(rule) => println!("{}: {}", dst, rule),
+                None => println!("{}: reject by exit policy", dst),
+            }
+        }
+        Decision::Refuse(phase, err) => println!("{}: refused at {}: {}", dst, phase, err),
+    }
+    Ok(())
+}
+
+fn upstream(config: &Config) -> String {
+    config
+        .upstream
+        .map(|addr| addr.to_string())
+        .unwrap_or_default()
+}
+
+fn parse_target(dst: &str) -> Result<Address, Socks5Error> {
+    let invalid = || Socks5Error::InvalidConfig(format!("invalid destination {}", dst));
+
+    let colon = dst.rfind(':').ok_or_else(invalid)?;
+    let host = dst[..colon].trim_start_matches('[').trim_end_matches(']');
+    let port = dst[colon + 1..].parse::<u16>().map_err(|_| invalid())?;
+
+    Ok(match host.parse::<IpAddr>() {
+        Ok(ip) => Address::from_ip(ip, port),
+        Err(_) if host.is_empty() => return Err(invalid()),
+        Err(_) => Address::Domain(host.to_string(), port),
+    })
+}
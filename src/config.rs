@@ -0,0 +1,1193 @@
+use crate::{
+    auth::{AuthMethod, Authenticator, NoAuth, StaticUserPass},
+    domains::DomainList,
+    errors::Socks5Error,
+    geoip::GeoIp,
+    policy::{parse_cidr, ExitPolicy},
+    report::MetricsSink,
+    resolver::{Resolver, SystemResolver, TcpFallbackResolver},
+    schedule::RateWindow,
+    server::ServerState,
+};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+// How a connection that fails before the tunnel is up gets turned away
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorMode {
+    Reply,
+    Close,
+    #[cfg(unix)]
+    Reset,
+}
+
+// How much gets logged, from only errors up to a line for every connection and, with
+// `trace`, every byte `--trace` dumps
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogLevel {
+    Err,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+// What a client that doesn't speak SOCKS5 gets to see
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decoy {
+    Http,
+    Backend(String),
+}
+
+// How a tunnel moves bytes once it's up; falls back to `Copy` where the kernel can't splice
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelayStrategy {
+    Copy,
+    #[cfg(target_os = "linux")]
+    Splice,
+}
+
+// Where the names in CONNECT and RESOLVE requests get looked up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DnsMode {
+    // Locally, unless chaining to an upstream that can look them up itself
+    Auto,
+    Local,
+    // Always by the upstream
+    Remote,
+}
+
+// What the `--upstream` proxy speaks
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UpstreamProtocol {
+    Socks5,
+    // HTTP CONNECT, authenticating with Proxy-Authorization
+    Http,
+}
+
+// Where outbound connections originate: a local address, or on Linux an interface
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutboundBind {
+    Addr(IpAddr),
+    #[cfg(target_os = "linux")]
+    Interface(u32),
+}
+
+impl std::fmt::Display for OutboundBind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutboundBind::Addr(ip) => write!(f, "{}", ip),
+            #[cfg(target_os = "linux")]
+            OutboundBind::Interface(index) => write!(f, "{}", index),
+        }
+    }
+}
+
+// Which address family gets the first connect attempt when a name has both
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+// Which kind of IPv6 source address outbound sockets should prefer (RFC 5014)
+#[cfg(target_os = "linux")]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourcePreference {
+    Temporary,
+    Public,
+}
+
+pub struct Config {
+    pub bind_addr: String,
+    pub max_connections: usize,
+    pub outbound_port_range: Option<(u16, u16)>,
+    pub outbound_bind: Option<OutboundBind>,
+    pub link_local_scope: Option<u32>,
+    pub nat64_prefix: Option<(Ipv6Addr, u8)>,
+    pub self_addrs: Vec<IpAddr>,
+    pub stall_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub handshake_timeout: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    // Head start each connect attempt gets before the next address is tried alongside it
+    // (RFC 8305); without it addresses are tried one after another
+    pub happy_eyeballs: Option<Duration>,
+    pub prefer_family: AddressFamily,
+    pub max_session: Option<Duration>,
+    pub dns_timeout: Option<Duration>,
+    // How long open connections get to finish after a shutdown; without it they're closed at once
+    pub drain_timeout: Option<Duration>,
+    // How long a user stays tied to the address it authenticated from
+    pub user_binding: Option<Duration>,
+    pub dns_retries: usize,
+    pub outbound_ttl: Option<u32>,
+    pub test_targets: Option<SocketAddr>,
+    pub trace: bool,
+    pub log_level: LogLevel,
+    pub log_json: bool,
+    pub on_error: ErrorMode,
+    pub preamble: Option<Vec<u8>>,
+    pub decoy: Option<Decoy>,
+    pub geoip: Option<GeoIp>,
+    pub relay: RelayStrategy,
+    pub print_capabilities: bool,
+    pub diagnose: bool,
+    // Where `--diagnose` checks that DNS and outbound connections work
+    pub probe_target: String,
+    pub exit_policy: Option<ExitPolicy>,
+    // Where the exit policy and users came from, when they're reloaded as the files change
+    pub exit_policy_file: Option<String>,
+    pub users_file: Option<String>,
+    // The users from `users_file`, for reloads to replace
+    pub(crate) file_users: Option<Arc<StaticUserPass>>,
+    // What the server started with this config keeps track of as it runs
+    pub(crate) state: ServerState,
+    pub print_exit_policy: bool,
+    pub shed_accept_rate: Option<u64>,
+    pub shed_failure_rate: Option<u64>,
+    pub rebind_protection: bool,
+    pub rebind_allow: Vec<String>,
+    // Appended to single-label names, tried in order
+    pub search_domains: Vec<String>,
+    // Never look up or forward a single-label name as it is, only with a search domain
+    pub reject_single_label: bool,
+    // Refuse loopback, link-local, RFC 1918 and ULA destinations, whatever the name
+    pub deny_private: bool,
+    pub authenticator: Arc<dyn Authenticator>,
+    // Private auth methods by ID, preferred in this order
+    pub auth_methods: Vec<(u8, Arc<dyn AuthMethod>)>,
+    pub resolver: Arc<dyn Resolver>,
+    pub metrics: Option<Arc<dyn MetricsSink>>,
+    pub report: Option<String>,
+    pub fast_reply: bool,
+    // Send 0.0.0.0:0 as BND for CONNECT rather than the outbound socket's address; BIND
+    // still tells the client who connected in
+    pub hide_bnd: bool,
+    // The public address BIND and UDP ASSOCIATE replies give out behind NAT, for clients that
+    // came in on the given local address or, without one, on any
+    pub external_addrs: Vec<(Option<IpAddr>, IpAddr)>,
+    // Also accept SOCKS4/4a CONNECT requests
+    pub socks4: bool,
+    // Track each tunnel's throughput, listed on SIGUSR1
+    pub tunnel_stats: bool,
+    // Where SIGUSR2 writes a JSON snapshot of every live connection
+    pub snapshot: Option<String>,
+    // Also serve HTTP CONNECT tunnels on the same port
+    pub http_connect: bool,
+    pub max_connections_per_ip: Option<usize>,
+    // Connections still negotiating, and tunnels past it, counted apart
+    pub max_handshakes: Option<usize>,
+    pub max_tunnels: Option<usize>,
+    pub priority_nets: Vec<(IpAddr, u8)>,
+    pub mirror: Option<SocketAddr>,
+    pub mirror_policy: Option<ExitPolicy>,
+    pub upstream: Option<SocketAddr>,
+    pub upstream_protocol: UpstreamProtocol,
+    pub upstream_user: Option<(String, String)>,
+    // Tag the username sent upstream with a hop count, to catch loops in a chain made
+    // only of this proxy; any other upstream would turn the tagged login away
+    pub upstream_hop_tag: bool,
+    pub dns_mode: DnsMode,
+    pub dns_reverse_map: Option<Duration>,
+    pub circuit_breaker: Option<usize>,
+    pub circuit_cooldown: Duration,
+    pub domain_allowlist: Option<DomainList>,
+    pub domain_blocklist: Option<DomainList>,
+    // Bytes per second, each way, per tunnel
+    pub rate_limit: Option<u64>,
+    // Override `rate_limit` while the local time falls in them, first match wins
+    pub rate_windows: Vec<RateWindow>,
+    #[cfg(target_os = "linux")]
+    pub unix_listen: Option<String>,
+    #[cfg(target_os = "linux")]
+    pub allow_uids: Vec<u32>,
+    #[cfg(target_os = "linux")]
+    pub allow_gids: Vec<u32>,
+    #[cfg(target_os = "linux")]
+    pub listen_filter: Option<String>,
+    #[cfg(target_os = "linux")]
+    pub acceptors: usize,
+    #[cfg(target_os = "linux")]
+    pub ipv6_source: Option<SourcePreference>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_addr: "0.0.0.0:1080".to_string(),
+            max_connections: 0,
+            outbound_port_range: None,
+            outbound_bind: None,
+            link_local_scope: None,
+            nat64_prefix: None,
+            self_addrs: vec![],
+            stall_timeout: None,
+            idle_timeout: None,
+            handshake_timeout: None,
+            connect_timeout: None,
+            happy_eyeballs: Some(crate::outbound::DEFAULT_ATTEMPT_DELAY),
+            prefer_family: AddressFamily::Ipv6,
+            max_session: None,
+            dns_timeout: None,
+            drain_timeout: None,
+            user_binding: None,
+            dns_retries: 0,
+            outbound_ttl: None,
+            test_targets: None,
+            trace: false,
+            log_level: LogLevel::Info,
+            log_json: false,
+            on_error: ErrorMode::Reply,
+            preamble: None,
+            decoy: None,
+            geoip: None,
+            relay: RelayStrategy::Copy,
+            print_capabilities: false,
+            diagnose: false,
+            probe_target: "example.com:80".to_string(),
+            exit_policy: None,
+            exit_policy_file: None,
+            users_file: None,
+            file_users: None,
+            state: ServerState::default(),
+            print_exit_policy: false,
+            shed_accept_rate: None,
+            shed_failure_rate: None,
+            rebind_protection: false,
+            rebind_allow: vec![],
+            search_domains: vec![],
+            reject_single_label: false,
+            deny_private: false,
+            authenticator: Arc::new(NoAuth),
+            auth_methods: vec![],
+            resolver: Arc::new(SystemResolver),
+            metrics: None,
+            report: None,
+            fast_reply: false,
+            hide_bnd: false,
+            external_addrs: vec![],
+            socks4: false,
+            tunnel_stats: false,
+            snapshot: None,
+            http_connect: false,
+            max_connections_per_ip: None,
+            max_handshakes: None,
+            max_tunnels: None,
+            priority_nets: vec![],
+            mirror: None,
+            mirror_policy: None,
+            upstream: None,
+            upstream_protocol: UpstreamProtocol::Socks5,
+            upstream_user: None,
+            upstream_hop_tag: false,
+            dns_mode: DnsMode::Auto,
+            dns_reverse_map: None,
+            circuit_breaker: None,
+            circuit_cooldown: crate::breaker::DEFAULT_COOLDOWN,
+            domain_allowlist: None,
+            domain_blocklist: None,
+            rate_limit: None,
+            rate_windows: vec![],
+            #[cfg(target_os = "linux")]
+            unix_listen: None,
+            #[cfg(target_os = "linux")]
+            allow_uids: vec![],
+            #[cfg(target_os = "linux")]
+            allow_gids: vec![],
+            #[cfg(target_os = "linux")]
+            listen_filter: None,
+            #[cfg(target_os = "linux")]
+            acceptors: 1,
+            #[cfg(target_os = "linux")]
+            ipv6_source: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Config, Socks5Error> {
+        let mut args = expand_config_files(args)?.into_iter();
+        let mut config = Config::default();
+        let mut users = vec![];
+        let mut upstream_url_user = None;
+        let mut exit_policy_default = None;
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--outbound-port-range" => {
+                    config.outbound_port_range =
+                        Some(parse_port_range(&next_value(&mut args, &arg)?)?);
+                }
+                "--link-local-scope" => {
+                    config.link_local_scope = Some(parse_scope(&next_value(&mut args, &arg)?)?);
+                }
+                "--nat64-prefix" => {
+                    config.nat64_prefix = Some(parse_nat64_prefix(&next_value(&mut args, &arg)?)?);
+                }
+                "--self-addr" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.self_addrs.push(value.parse().map_err(|_| {
+                        Socks5Error::InvalidConfig(format!("invalid address {}", value))
+                    })?);
+                }
+                "--external-addr" => {
+                    config
+                        .external_addrs
+                        .push(parse_external_addr(&next_value(&mut args, &arg)?)?);
+                }
+                "--stall-timeout" => {
+                    config.stall_timeout = Some(parse_secs(&next_value(&mut args, &arg)?)?);
+                }
+                "--idle-timeout" => {
+                    config.idle_timeout = Some(parse_secs(&next_value(&mut args, &arg)?)?);
+                }
+                "--handshake-timeout" => {
+                    config.handshake_timeout = Some(parse_secs(&next_value(&mut args, &arg)?)?);
+                }
+                "--connect-timeout" => {
+                    config.connect_timeout = Some(parse_secs(&next_value(&mut args, &arg)?)?);
+                }
+                "--happy-eyeballs" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.happy_eyeballs = match value.parse::<u64>() {
+                        Ok(0) => None,
+                        Ok(ms) => Some(Duration::from_millis(ms)),
+                        Err(_) => {
+                            return Err(Socks5Error::InvalidConfig(format!(
+                                "invalid milliseconds {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+                "--prefer-family" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.prefer_family = match value.as_str() {
+                        "ipv4" => AddressFamily::Ipv4,
+                        "ipv6" => AddressFamily::Ipv6,
+                        _ => {
+                            return Err(Socks5Error::InvalidConfig(format!(
+                                "invalid address family {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+                "--max-session" => {
+                    config.max_session = Some(parse_secs(&next_value(&mut args, &arg)?)?);
+                }
+                "--dns-timeout" => {
+                    config.dns_timeout = Some(parse_secs(&next_value(&mut args, &arg)?)?);
+                }
+                "--drain-timeout" => {
+                    config.drain_timeout = Some(parse_secs(&next_value(&mut args, &arg)?)?);
+                }
+                "--user-binding" => {
+                    config.user_binding = Some(parse_secs(&next_value(&mut args, &arg)?)?);
+                }
+                "--circuit-breaker" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.circuit_breaker = match value.parse::<usize>() {
+                        Ok(n) if n > 0 => Some(n),
+                        _ => {
+                            return Err(Socks5Error::InvalidConfig(format!(
+                                "invalid failure count {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+                "--circuit-cooldown" => {
+                    config.circuit_cooldown = parse_secs(&next_value(&mut args, &arg)?)?;
+                }
+                "--dns-reverse-map" => {
+                    config.dns_reverse_map = Some(parse_secs(&next_value(&mut args, &arg)?)?);
+                }
+                "--dns-retries" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.dns_retries = value.parse().map_err(|_| {
+                        Socks5Error::InvalidConfig(format!("invalid retry count {}", value))
+                    })?;
+                }
+                // getaddrinfo keeps truncation to itself, so this takes a resolver that doesn't
+                "--dns-tcp-fallback" => config.resolver = Arc::new(TcpFallbackResolver),
+                "--outbound-bind" => {
+                    config.outbound_bind =
+                        Some(parse_outbound_bind(&next_value(&mut args, &arg)?)?);
+                }
+                "--outbound-ttl" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.outbound_ttl = match value.parse::<u32>() {
+                        Ok(ttl) if ttl > 0 && ttl <= 255 => Some(ttl),
+                        _ => {
+                            return Err(Socks5Error::InvalidConfig(format!(
+                                "invalid TTL {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+                "--trace" => config.trace = true,
+                "--log-level" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.log_level = match value.as_str() {
+                        "err" => LogLevel::Err,
+                        "warn" => LogLevel::Warn,
+                        "info" => LogLevel::Info,
+                        "debug" => LogLevel::Debug,
+                        "trace" => LogLevel::Trace,
+                        _ => {
+                            return Err(Socks5Error::InvalidConfig(format!(
+                                "invalid log level {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+                "--log-format" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.log_json = match value.as_str() {
+                        "text" => false,
+                        "json" => true,
+                        _ => {
+                            return Err(Socks5Error::InvalidConfig(format!(
+                                "invalid log format {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+                "--preamble" => {
+                    let value = next_value(&mut args, &arg)?;
+                    if value.is_empty() {
+                        return Err(Socks5Error::InvalidConfig("empty preamble".to_string()));
+                    }
+                    config.preamble = Some(value.into_bytes());
+                }
+                "--on-error" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.on_error = match value.as_str() {
+                        "reply" => ErrorMode::Reply,
+                        "close" => ErrorMode::Close,
+                        #[cfg(unix)]
+                        "reset" => ErrorMode::Reset,
+                        _ => {
+                            return Err(Socks5Error::InvalidConfig(format!(
+                                "invalid error mode {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+                "--test-targets" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.test_targets = match value.parse::<SocketAddr>() {
+                        // The discard and chargen services take the two ports after it
+                        Ok(addr) if addr.port() != 0 && addr.port() <= u16::MAX - 2 => Some(addr),
+                        _ => {
+                            return Err(Socks5Error::InvalidConfig(format!(
+                                "invalid test target address {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+                "--decoy" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.decoy = Some(match value.as_str() {
+                        "http" => Decoy::Http,
+                        _ => Decoy::Backend(value),
+                    });
+                }
+                "--geoip" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.geoip = Some(
+                        GeoIp::load(&value)
+                            .map_err(|err| Socks5Error::InvalidConfig(err.to_string()))?,
+                    );
+                }
+                "--relay" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.relay = match value.as_str() {
+                        "copy" => RelayStrategy::Copy,
+                        #[cfg(target_os = "linux")]
+                        "splice" => RelayStrategy::Splice,
+                        _ => {
+                            return Err(Socks5Error::InvalidConfig(format!(
+                                "invalid relay strategy {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+                "--print-capabilities" => config.print_capabilities = true,
+                "--diagnose" => config.diagnose = true,
+                "--probe-target" => config.probe_target = next_value(&mut args, &arg)?,
+                "--exit-policy" | "--exit-policy-file" if config.exit_policy.is_some() => {
+                    return Err(Socks5Error::InvalidConfig(
+                        "only one of --exit-policy and --exit-policy-file can be given".to_string(),
+                    ));
+                }
+                "--exit-policy" => {
+                    config.exit_policy = Some(ExitPolicy::parse(&next_value(&mut args, &arg)?)?);
+                }
+                "--exit-policy-file" => {
+                    let path = next_value(&mut args, &arg)?;
+                    config.exit_policy = Some(ExitPolicy::parse(&read_file(&path)?)?);
+                    config.exit_policy_file = Some(path);
+                }
+                "--users-file" => {
+                    let path = next_value(&mut args, &arg)?;
+                    let users = parse_users_file(&read_file(&path)?)?;
+                    config.file_users = Some(Arc::new(StaticUserPass::new(users)));
+                    config.users_file = Some(path);
+                }
+                "--exit-policy-default" => {
+                    let value = next_value(&mut args, &arg)?;
+                    exit_policy_default = match value.as_str() {
+                        "accept" => Some(true),
+                        "reject" => Some(false),
+                        _ => {
+                            return Err(Socks5Error::InvalidConfig(format!(
+                                "invalid exit policy default {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+                "--print-exit-policy" => config.print_exit_policy = true,
+                "--user" => users.push(parse_user(&next_value(&mut args, &arg)?)?),
+                "--max-connections" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.max_connections = value.parse().map_err(|_| {
+                        Socks5Error::InvalidConfig(format!("invalid connection limit {}", value))
+                    })?;
+                }
+                "--max-connections-per-ip" => {
+                    config.max_connections_per_ip =
+                        Some(parse_limit(&next_value(&mut args, &arg)?)?);
+                }
+                "--max-handshakes" => {
+                    config.max_handshakes = Some(parse_limit(&next_value(&mut args, &arg)?)?);
+                }
+                "--max-tunnels" => {
+                    config.max_tunnels = Some(parse_limit(&next_value(&mut args, &arg)?)?);
+                }
+                "--priority-net" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.priority_nets.push(parse_cidr(&value).ok_or_else(|| {
+                        Socks5Error::InvalidConfig(format!("invalid network {}", value))
+                    })?);
+                }
+                "--mirror" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.mirror = Some(value.parse().map_err(|_| {
+                        Socks5Error::InvalidConfig(format!("invalid mirror address {}", value))
+                    })?);
+                }
+                "--mirror-policy" => {
+                    config.mirror_policy = Some(ExitPolicy::parse(&next_value(&mut args, &arg)?)?);
+                }
+                "--upstream" => {
+                    let (protocol, addr, user) = parse_upstream(&next_value(&mut args, &arg)?)?;
+                    config.upstream = Some(addr);
+                    config.upstream_protocol = protocol;
+                    upstream_url_user = user;
+                }
+                "--upstream-user" => {
+                    config.upstream_user = Some(parse_user(&next_value(&mut args, &arg)?)?);
+                }
+                "--upstream-hop-tag" => config.upstream_hop_tag = true,
+                "--dns-mode" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.dns_mode = match value.as_str() {
+                        "auto" => DnsMode::Auto,
+                        "local" => DnsMode::Local,
+                        "remote" => DnsMode::Remote,
+                        _ => {
+                            return Err(Socks5Error::InvalidConfig(format!(
+                                "invalid dns mode {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+                "--fast-reply" => config.fast_reply = true,
+                "--hide-bnd" => config.hide_bnd = true,
+                "--socks4" => config.socks4 = true,
+                "--tunnel-stats" => config.tunnel_stats = true,
+                "--snapshot" => config.snapshot = Some(next_value(&mut args, &arg)?),
+                "--http-connect" => config.http_connect = true,
+                "--report" => config.report = Some(next_value(&mut args, &arg)?),
+                "--domain-allowlist" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.domain_allowlist = Some(
+                        DomainList::load(&value)
+                            .map_err(|err| Socks5Error::InvalidConfig(err.to_string()))?,
+                    );
+                }
+                "--domain-blocklist" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.domain_blocklist = Some(
+                        DomainList::load(&value)
+                            .map_err(|err| Socks5Error::InvalidConfig(err.to_string()))?,
+                    );
+                }
+                "--rebind-protection" => config.rebind_protection = true,
+                "--deny-private" => config.deny_private = true,
+                "--rebind-allow" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config
+                        .rebind_allow
+                        .push(value.trim_end_matches('.').to_ascii_lowercase());
+                }
+                "--search-domain" => {
+                    let value = next_value(&mut args, &arg)?;
+                    let domain = value.trim_matches('.').to_ascii_lowercase();
+                    if domain.is_empty() {
+                        return Err(Socks5Error::InvalidConfig(format!(
+                            "invalid search domain {}",
+                            value
+                        )));
+                    }
+                    config.search_domains.push(domain);
+                }
+                "--reject-single-label" => config.reject_single_label = true,
+                "--rate-limit" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.rate_limit =
+                        Some(parse_rate(&value)?.checked_mul(1024).ok_or_else(|| {
+                            Socks5Error::InvalidConfig(format!("invalid rate {}", value))
+                        })?);
+                }
+                "--rate-window" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config
+                        .rate_windows
+                        .push(RateWindow::parse(&value).ok_or_else(|| {
+                            Socks5Error::InvalidConfig(format!("invalid rate window {}", value))
+                        })?);
+                }
+                "--shed-accept-rate" => {
+                    config.shed_accept_rate = Some(parse_rate(&next_value(&mut args, &arg)?)?);
+                }
+                "--shed-failure-rate" => {
+                    config.shed_failure_rate = Some(parse_rate(&next_value(&mut args, &arg)?)?);
+                }
+                #[cfg(target_os = "linux")]
+                "--unix-listen" => config.unix_listen = Some(next_value(&mut args, &arg)?),
+                #[cfg(target_os = "linux")]
+                "--allow-uid" => config
+                    .allow_uids
+                    .push(parse_id(&next_value(&mut args, &arg)?)?),
+                #[cfg(target_os = "linux")]
+                "--allow-gid" => config
+                    .allow_gids
+                    .push(parse_id(&next_value(&mut args, &arg)?)?),
+                #[cfg(target_os = "linux")]
+                "--listen-filter" => config.listen_filter = Some(next_value(&mut args, &arg)?),
+                #[cfg(target_os = "linux")]
+                "--acceptors" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.acceptors = match value.parse::<usize>() {
+                        Ok(n) if n > 0 => n,
+                        _ => {
+                            return Err(Socks5Error::InvalidConfig(format!(
+                                "invalid acceptor count {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+                #[cfg(target_os = "linux")]
+                "--ipv6-source" => {
+                    let value = next_value(&mut args, &arg)?;
+                    config.ipv6_source = match value.as_str() {
+                        "temporary" => Some(SourcePreference::Temporary),
+                        "public" => Some(SourcePreference::Public),
+                        _ => {
+                            return Err(Socks5Error::InvalidConfig(format!(
+                                "invalid IPv6 source preference {}",
+                                value
+                            )))
+                        }
+                    };
+                }
+                _ if arg.starts_with("--") => {
+                    return Err(Socks5Error::InvalidConfig(format!(
+                        "unknown option {}",
+                        arg
+                    )));
+                }
+                _ => config.bind_addr = arg,
+            }
+        }
+
+        // A default without rules is a policy of its own, e.g. `reject` to allow nothing
+        if let Some(accept) = exit_policy_default {
+            if config.exit_policy.is_none() {
+                config.exit_policy = Some(ExitPolicy::parse("")?);
+            }
+            if let Some(policy) = &mut config.exit_policy {
+                policy.set_default(accept);
+            }
+        }
+
+        // Rebind protection and --deny-private have to see the addresses, which remote lookups never show
+        if config.dns_mode == DnsMode::Remote {
+            if config.upstream.is_none() {
+                return Err(Socks5Error::InvalidConfig(
+                    "--dns-mode remote needs --upstream".to_string(),
+                ));
+            }
+            if config.rebind_protection || config.deny_private {
+                return Err(Socks5Error::InvalidConfig(
+                    "--dns-mode remote can't be used with --rebind-protection or --deny-private"
+                        .to_string(),
+                ));
+            }
+        }
+
+        // Credentials written into the upstream's URL are that upstream's own
+        if let Some(user) = upstream_url_user {
+            if config.upstream_user.is_some() {
+                return Err(Socks5Error::InvalidConfig(
+                    "--upstream-user and credentials in --upstream can't both be given".to_string(),
+                ));
+            }
+            config.upstream_user = Some(user);
+        }
+        // An HTTP proxy only has CONNECT, and no username to tag
+        if config.upstream_protocol == UpstreamProtocol::Http {
+            if config.dns_mode == DnsMode::Remote {
+                return Err(Socks5Error::InvalidConfig(
+                    "--dns-mode remote needs a SOCKS5 --upstream".to_string(),
+                ));
+            }
+            if config.upstream_hop_tag {
+                return Err(Socks5Error::InvalidConfig(
+                    "--upstream-hop-tag needs a SOCKS5 --upstream".to_string(),
+                ));
+            }
+        }
+
+        // Chaining to the proxy's own listener would feed every tunnel straight back into it
+        if let (Some(upstream), Ok(listen)) =
+            (config.upstream, config.bind_addr.parse::<SocketAddr>())
+        {
+            let local = upstream.ip().is_unspecified()
+                || (listen.ip().is_unspecified() && upstream.ip().is_loopback());
+            if (local && upstream.port() == listen.port())
+                || crate::server::self_addrs(listen, &config).contains(&upstream)
+            {
+                return Err(Socks5Error::InvalidConfig(format!(
+                    "--upstream {} is this proxy's own listener",
+                    upstream
+                )));
+            }
+        }
+
+        // The tag rides on the username, which has to stay within RFC 1929's 255 bytes.
+        if config.upstream_hop_tag {
+            match &config.upstream_user {
+                Some((user, _)) if user.len() + crate::upstream::HOP_TAG_LEN <= 255 => {}
+                Some(_) => {
+                    return Err(Socks5Error::InvalidConfig(
+                        "--upstream-user is too long to carry --upstream-hop-tag".to_string(),
+                    ))
+                }
+                // The end of a chain only strips the tags it's sent
+                None if config.upstream.is_none() => {}
+                None => {
+                    return Err(Socks5Error::InvalidConfig(
+                        "--upstream-hop-tag needs --upstream-user".to_string(),
+                    ))
+                }
+            }
+        }
+
+        if let Some(file_users) = &config.file_users {
+            if !users.is_empty() {
+                return Err(Socks5Error::InvalidConfig(
+                    "--user and --users-file can't both be given".to_string(),
+                ));
+            }
+            config.authenticator = file_users.clone();
+        }
+        if !users.is_empty() {
+            config.authenticator = Arc::new(StaticUserPass::new(users));
+        }
+
+        Ok(config)
+    }
+}
+
+// Splices the options from each `--config FILE` in where it appears, so flags after it override the file
+fn expand_config_files(mut args: impl Iterator<Item = String>) -> Result<Vec<String>, Socks5Error> {
+    let mut expanded = vec![];
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            expanded.extend(config_file_args(&next_value(&mut args, &arg)?)?);
+        } else {
+            expanded.push(arg);
+        }
+    }
+    Ok(expanded)
+}
+
+// A TOML config file is a flat table of the command line options without their dashes:
+// `listen = "0.0.0.0:1080"`, `max-connections = 512`, `trace = true`, `user = ["alice:secret"]`.
+// Arrays stand for an option given several times. Options come out in the order the file
+// has them, and anything wrong is reported with the line it's on.
+fn config_file_args(path: &str) -> Result<Vec<String>, Socks5Error> {
+    let invalid = |msg: String| Socks5Error::InvalidConfig(format!("{}: {}", path, msg));
+
+    let content = std::fs::read_to_string(path).map_err(|err| invalid(err.to_string()))?;
+    let table = toml::from_str::<BTreeMap<String, toml::Spanned<toml::Value>>>(&content)
+        .map_err(|err| invalid(err.to_string()))?;
+    let mut entries = table.into_iter().collect::<Vec<_>>();
+    entries.sort_by_key(|(_, value)| value.start());
+
+    let mut args = vec![];
+    for (key, value) in entries {
+        let line = content[..value.start()].matches('\n').count() + 1;
+        let invalid = |msg: String| invalid(format!("line {}: {}", line, msg));
+        let kind = match config_key(&key) {
+            Some(kind) => kind,
+            None if key == "config" => {
+                return Err(invalid("config files can't be nested".to_string()))
+            }
+            None => return Err(invalid(format!("unknown option {}", key))),
+        };
+
+        let values = match (kind, value.into_inner()) {
+            (ConfigKey::Value, toml::Value::Array(values)) => values,
+            (_, value) => vec![value],
+        };
+        for value in values {
+            match (kind, value) {
+                (ConfigKey::Flag, toml::Value::Boolean(true)) => args.push(format!("--{}", key)),
+                (ConfigKey::Flag, toml::Value::Boolean(false)) => {}
+                (ConfigKey::Flag, _) => {
+                    return Err(invalid(format!(
+                        "{} is a switch, expected true or false",
+                        key
+                    )))
+                }
+                (ConfigKey::Listen, toml::Value::String(value)) => args.push(value),
+                (ConfigKey::Value, toml::Value::String(value)) => {
+                    args.extend(vec![format!("--{}", key), value])
+                }
+                (ConfigKey::Value, toml::Value::Integer(value)) => {
+                    args.extend(vec![format!("--{}", key), value.to_string()])
+                }
+                (_, _) => {
+                    return Err(invalid(format!(
+                        "{} takes a value, expected a string{}",
+                        key,
+                        if kind == ConfigKey::Value {
+                            ", an integer or an array of them"
+                        } else {
+                            ""
+                        }
+                    )))
+                }
+            }
+        }
+    }
+    Ok(args)
+}
+
+// How a config file key is given on the command line
+#[derive(Clone, Copy, PartialEq)]
+enum ConfigKey {
+    // The listen address, which goes without a flag
+    Listen,
+    // An option without a value, set with `true`
+    Flag,
+    // An option taking a value, which an array gives several times over
+    Value,
+}
+
+fn config_key(key: &str) -> Option<ConfigKey> {
+    let kind = match key {
+        "listen" => ConfigKey::Listen,
+        "dns-tcp-fallback"
+        | "trace"
+        | "print-capabilities"
+        | "diagnose"
+        | "print-exit-policy"
+        | "upstream-hop-tag"
+        | "fast-reply"
+        | "hide-bnd"
+        | "socks4"
+        | "tunnel-stats"
+        | "http-connect"
+        | "rebind-protection"
+        | "deny-private"
+        | "reject-single-label" => ConfigKey::Flag,
+        "outbound-port-range"
+        | "link-local-scope"
+        | "nat64-prefix"
+        | "self-addr"
+        | "external-addr"
+        | "stall-timeout"
+        | "idle-timeout"
+        | "handshake-timeout"
+        | "connect-timeout"
+        | "happy-eyeballs"
+        | "prefer-family"
+        | "max-session"
+        | "dns-timeout"
+        | "drain-timeout"
+        | "user-binding"
+        | "circuit-breaker"
+        | "circuit-cooldown"
+        | "dns-reverse-map"
+        | "dns-retries"
+        | "outbound-bind"
+        | "outbound-ttl"
+        | "log-level"
+        | "log-format"
+        | "preamble"
+        | "on-error"
+        | "test-targets"
+        | "decoy"
+        | "geoip"
+        | "relay"
+        | "probe-target"
+        | "exit-policy"
+        | "exit-policy-file"
+        | "users-file"
+        | "exit-policy-default"
+        | "user"
+        | "max-connections"
+        | "max-connections-per-ip"
+        | "max-handshakes"
+        | "max-tunnels"
+        | "priority-net"
+        | "mirror"
+        | "mirror-policy"
+        | "upstream"
+        | "upstream-user"
+        | "dns-mode"
+        | "snapshot"
+        | "report"
+        | "domain-allowlist"
+        | "domain-blocklist"
+        | "rebind-allow"
+        | "search-domain"
+        | "rate-limit"
+        | "rate-window"
+        | "shed-accept-rate"
+        | "shed-failure-rate" => ConfigKey::Value,
+        #[cfg(target_os = "linux")]
+        "unix-listen" | "allow-uid" | "allow-gid" | "listen-filter" | "acceptors"
+        | "ipv6-source" => ConfigKey::Value,
+        _ => return None,
+    };
+    Some(kind)
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, Socks5Error> {
+    args.next()
+        .ok_or_else(|| Socks5Error::InvalidConfig(format!("missing value for {}", flag)))
+}
+
+#[cfg(target_os = "linux")]
+fn parse_id(s: &str) -> Result<u32, Socks5Error> {
+    s.parse::<u32>()
+        .map_err(|_| Socks5Error::InvalidConfig(format!("invalid uid/gid {}", s)))
+}
+
+fn parse_secs(s: &str) -> Result<Duration, Socks5Error> {
+    match s.parse::<u64>() {
+        Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(Socks5Error::InvalidConfig(format!("invalid seconds {}", s))),
+    }
+}
+
+// Accepts `name:password`; RFC 1929 caps both at 255 bytes
+fn parse_user(s: &str) -> Result<Credentials, Socks5Error> {
+    match s.split_once(':') {
+        Some((user, pass))
+            if !user.is_empty() && user.len() <= 255 && !pass.is_empty() && pass.len() <= 255 =>
+        {
+            Ok((user.to_string(), pass.to_string()))
+        }
+        _ => Err(Socks5Error::InvalidConfig(
+            "invalid user, expected name:password".to_string(),
+        )),
+    }
+}
+
+// A name and password, as --user and --upstream-user take them
+type Credentials = (String, String);
+
+// `ADDR`, or `socks5://ADDR` or `http://ADDR` with an optional percent-encoded `USER:PASS@`
+// before the address
+fn parse_upstream(
+    s: &str,
+) -> Result<(UpstreamProtocol, SocketAddr, Option<Credentials>), Socks5Error> {
+    let invalid = || Socks5Error::InvalidConfig("invalid upstream".to_string());
+    let (protocol, rest) = match s.split_once("://") {
+        None => (UpstreamProtocol::Socks5, s),
+        Some((scheme, rest)) => match scheme.to_ascii_lowercase().as_str() {
+            "socks5" => (UpstreamProtocol::Socks5, rest),
+            "http" => (UpstreamProtocol::Http, rest),
+            _ => {
+                return Err(Socks5Error::InvalidConfig(format!(
+                    "unsupported upstream scheme {}",
+                    scheme
+                )))
+            }
+        },
+    };
+    let rest = rest.trim_end_matches('/');
+    let (user, addr) = match rest.rsplit_once('@') {
+        Some((userinfo, addr)) => {
+            let (user, pass) = userinfo.split_once(':').ok_or_else(invalid)?;
+            let user = crate::client::percent_decode(user).ok_or_else(invalid)?;
+            let pass = crate::client::percent_decode(pass).ok_or_else(invalid)?;
+            // Checked as --upstream-user is, RFC 1929 limits applying to both protocols alike
+            (Some(parse_user(&format!("{}:{}", user, pass))?), addr)
+        }
+        None => (None, rest),
+    };
+    let addr = addr
+        .parse()
+        .map_err(|_| Socks5Error::InvalidConfig(format!("invalid upstream address {}", addr)))?;
+    Ok((protocol, addr, user))
+}
+
+fn read_file(path: &str) -> Result<String, Socks5Error> {
+    std::fs::read_to_string(path)
+        .map_err(|err| Socks5Error::InvalidConfig(format!("{}: {}", path, err)))
+}
+
+// `name:password` a line, with blank lines and `#` comments skipped. No users at all is
+// taken for a file caught half written rather than a wish to let nobody in.
+pub(crate) fn parse_users_file(content: &str) -> Result<Vec<Credentials>, Socks5Error> {
+    let users = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_user)
+        .collect::<Result<Vec<_>, _>>()?;
+    if users.is_empty() {
+        return Err(Socks5Error::InvalidConfig("no users".to_string()));
+    }
+    Ok(users)
+}
+
+fn parse_limit(s: &str) -> Result<usize, Socks5Error> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(Socks5Error::InvalidConfig(format!(
+            "invalid connection limit {}",
+            s
+        ))),
+    }
+}
+
+fn parse_rate(s: &str) -> Result<u64, Socks5Error> {
+    match s.parse::<u64>() {
+        Ok(rate) if rate > 0 => Ok(rate),
+        _ => Err(Socks5Error::InvalidConfig(format!("invalid rate {}", s))),
+    }
+}
+
+// Accepts `PUBLIC` or `LOCAL=PUBLIC`
+fn parse_external_addr(s: &str) -> Result<(Option<IpAddr>, IpAddr), Socks5Error> {
+    let invalid = || Socks5Error::InvalidConfig(format!("invalid external address {}", s));
+
+    let (local, external) = match s.split_once('=') {
+        Some((local, external)) => (Some(local.parse().map_err(|_| invalid())?), external),
+        None => (None, s),
+    };
+    Ok((local, external.parse().map_err(|_| invalid())?))
+}
+
+// Accepts `lo-hi`, both ends inclusive
+fn parse_port_range(s: &str) -> Result<(u16, u16), Socks5Error> {
+    let invalid = || Socks5Error::InvalidConfig(format!("invalid port range {}", s));
+
+    let mut parts = s.splitn(2, '-');
+    let lo = parts
+        .next()
+        .and_then(|p| p.trim().parse::<u16>().ok())
+        .ok_or_else(invalid)?;
+    let hi = parts
+        .next()
+        .and_then(|p| p.trim().parse::<u16>().ok())
+        .ok_or_else(invalid)?;
+
+    if lo == 0 || lo > hi {
+        return Err(invalid());
+    }
+    Ok((lo, hi))
+}
+
+// A local address, or an interface by name or index
+pub(crate) fn parse_outbound_bind(s: &str) -> Result<OutboundBind, Socks5Error> {
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return Ok(OutboundBind::Addr(ip));
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        parse_scope(s).map(OutboundBind::Interface)
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        Err(Socks5Error::InvalidConfig(format!(
+            "invalid outbound bind address {}",
+            s
+        )))
+    }
+}
+
+// Accepts either a numeric scope id or an interface name
+fn parse_scope(s: &str) -> Result<u32, Socks5Error> {
+    if let Ok(index) = s.parse::<u32>() {
+        return Ok(index);
+    }
+
+    #[cfg(unix)]
+    {
+        if let Ok(name) = std::ffi::CString::new(s) {
+            let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+            if index != 0 {
+                return Ok(index);
+            }
+        }
+    }
+
+    Err(Socks5Error::InvalidConfig(format!(
+        "unknown interface {}",
+        s
+    )))
+}
+
+// RFC 6052 only defines these prefix lengths
+fn parse_nat64_prefix(s: &str) -> Result<(Ipv6Addr, u8), Socks5Error> {
+    let invalid = || Socks5Error::InvalidConfig(format!("invalid NAT64 prefix {}", s));
+
+    let mut parts = s.splitn(2, '/');
+    let prefix = parts
+        .next()
+        .and_then(|p| p.parse::<Ipv6Addr>().ok())
+        .ok_or_else(invalid)?;
+    let len = match parts.next() {
+        Some(len) => len.parse::<u8>().map_err(|_| invalid())?,
+        None => 96,
+    };
+
+    match len {
+        32 | 40 | 48 | 56 | 64 | 96 => Ok((prefix, len)),
+        _ => Err(invalid()),
+    }
+}
@@ -0,0 +1,84 @@
+use crate::{
+    config::{Config, Decoy},
+    ioutil::{copy_bidirectional, CopyOptions, Stream},
+};
+use async_std::{io, net::TcpStream, prelude::*};
+use std::time::Duration;
+
+const HEADER_LIMIT: usize = 8192;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+const NOT_FOUND: &str = "<html>\r\n<head><title>404 Not Found</title></head>\r\n<body>\r\n<center><h1>404 Not Found</h1></center>\r\n<hr><center>nginx</center>\r\n</body>\r\n</html>\r\n";
+const BAD_REQUEST: &str = "<html>\r\n<head><title>400 Bad Request</title></head>\r\n<body>\r\n<center><h1>400 Bad Request</h1></center>\r\n<hr><center>nginx</center>\r\n</body>\r\n</html>\r\n";
+
+// Answers a client that isn't speaking SOCKS5, given the bytes already read from it,
+// so that probing the port turns up an ordinary web server
+pub(crate) async fn serve<S: Stream>(
+    mut stream: S,
+    read: &[u8],
+    decoy: &Decoy,
+    config: &Config,
+) -> io::Result<()> {
+    match decoy {
+        Decoy::Http => http(&mut stream, read).await,
+        Decoy::Backend(addr) => {
+            let mut backend = TcpStream::connect(addr.as_str()).await?;
+            backend.write_all(read).await?;
+            copy_bidirectional(
+                stream,
+                backend,
+                CopyOptions {
+                    stall_timeout: config.stall_timeout,
+                    ..CopyOptions::default()
+                },
+            )
+            .await
+            .map(|_| ())
+        }
+    }
+}
+
+async fn http<S: Stream>(stream: &mut S, read: &[u8]) -> io::Result<()> {
+    let mut request = read.to_vec();
+
+    // Wait for the whole header like a real server would, but not forever
+    let _ = io::timeout(HEADER_TIMEOUT, async {
+        let mut buf = [0u8; 1024];
+        while request.len() < HEADER_LIMIT && !ends_header(&request) {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok(())
+    })
+    .await;
+
+    let (status, body) = if looks_like_http(&request) {
+        ("404 Not Found", NOT_FOUND)
+    } else {
+        ("400 Bad Request", BAD_REQUEST)
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nServer: nginx\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
+
+fn ends_header(request: &[u8]) -> bool {
+    request.windows(4).any(|w| w == b"\r\n\r\n")
+}
+
+// A request line starts with an upper-case method followed by a space
+fn looks_like_http(request: &[u8]) -> bool {
+    let method_len = request
+        .iter()
+        .take_while(|b| b.is_ascii_uppercase())
+        .count();
+    method_len > 0 && request.get(method_len) == Some(&b' ')
+}
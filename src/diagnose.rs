@@ -0,0 +1,174 @@
+use crate::config::Config;
+use async_std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+// How long the DNS and connect probes get when no timeout is configured
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// `--diagnose`: checks what the server depends on at runtime, prints one line per check
+// and returns 1 if any of them failed. Capabilities that are merely missing don't count.
+pub fn run(config: &Config) -> i32 {
+    let mut failed = false;
+    let mut report = |name: &str, res: Result<String, String>| match res {
+        Ok(detail) => println!("{}: ok ({})", name, detail),
+        Err(detail) => {
+            println!("{}: FAILED ({})", name, detail);
+            failed = true;
+        }
+    };
+
+    report("resolv.conf", resolv_conf());
+    let resolved = futures::executor::block_on(resolve(config));
+    report(
+        "dns",
+        resolved
+            .as_ref()
+            .map(|addrs| format_addrs(addrs))
+            .map_err(|err| err.clone()),
+    );
+    if let Ok(addrs) = resolved {
+        report(
+            "outbound",
+            futures::executor::block_on(connect(&addrs, config)),
+        );
+    }
+    report("fd limit", fd_limit());
+
+    #[cfg(target_os = "linux")]
+    {
+        println!(
+            "splice: {}",
+            if crate::splice::supported() {
+                "supported"
+            } else {
+                "unsupported"
+            }
+        );
+        println!("tcp fast open: {}", tcp_fastopen());
+        println!("tproxy: {}", tproxy());
+    }
+
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+fn resolv_conf() -> Result<String, String> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf")
+        .map_err(|err| format!("/etc/resolv.conf: {}", err))?;
+    let servers = conf
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .map(|server| server.trim().to_string())
+        .collect::<Vec<_>>();
+    if servers.is_empty() {
+        Err("no nameserver in /etc/resolv.conf".to_string())
+    } else {
+        Ok(format!("nameservers {}", servers.join(" ")))
+    }
+}
+
+async fn resolve(config: &Config) -> Result<Vec<SocketAddr>, String> {
+    let target = &config.probe_target;
+    let (host, port) = target
+        .rsplit_once(':')
+        .and_then(|(host, port)| {
+            Some((
+                host.trim_matches(|c| c == '[' || c == ']'),
+                port.parse().ok()?,
+            ))
+        })
+        .ok_or_else(|| format!("invalid probe target {}", target))?;
+
+    let lookup = crate::resolver::lookup_host(host, config);
+    let ips = io::timeout(config.dns_timeout.unwrap_or(PROBE_TIMEOUT), lookup)
+        .await
+        .map_err(|err| format!("{}: {}", host, err))?;
+    Ok(ips
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
+
+async fn connect(addrs: &[SocketAddr], config: &Config) -> Result<String, String> {
+    let attempt = crate::outbound::connect(addrs, config);
+    let stream = io::timeout(config.connect_timeout.unwrap_or(PROBE_TIMEOUT), attempt)
+        .await
+        .map_err(|err| format!("{}: {}", config.probe_target, err))?;
+    Ok(match (stream.local_addr(), stream.peer_addr()) {
+        (Ok(local), Ok(peer)) => format!("{} -> {}", local, peer),
+        _ => config.probe_target.clone(),
+    })
+}
+
+fn format_addrs(addrs: &[SocketAddr]) -> String {
+    addrs
+        .iter()
+        .map(|addr| addr.ip().to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn fd_limit() -> Result<String, String> {
+    #[cfg(unix)]
+    {
+        let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+        let detail = format!("soft {}, hard {}", limit.rlim_cur, limit.rlim_max);
+        // Every tunnel takes two descriptors, so anything this low caps it at a few hundred
+        if limit.rlim_cur < 1024 {
+            Err(detail)
+        } else {
+            Ok(detail)
+        }
+    }
+    #[cfg(not(unix))]
+    Ok("unknown".to_string())
+}
+
+#[cfg(target_os = "linux")]
+fn tcp_fastopen() -> String {
+    match std::fs::read_to_string("/proc/sys/net/ipv4/tcp_fastopen") {
+        // Bit 0 enables it for clients, bit 1 for servers
+        Ok(value) => match value.trim().parse::<u32>() {
+            Ok(flags) if flags & 3 == 3 => "client and server".to_string(),
+            Ok(flags) if flags & 1 == 1 => "client only".to_string(),
+            Ok(flags) if flags & 2 == 2 => "server only".to_string(),
+            Ok(_) => "disabled".to_string(),
+            Err(_) => format!("unknown ({})", value.trim()),
+        },
+        Err(err) => format!("unknown ({})", err),
+    }
+}
+
+// IP_TRANSPARENT needs CAP_NET_ADMIN, so setting it on a throwaway socket says whether TPROXY would work
+#[cfg(target_os = "linux")]
+fn tproxy() -> String {
+    use socket2::{Domain, Protocol, Socket, Type};
+    use std::os::unix::io::AsRawFd;
+
+    let socket = match Socket::new(Domain::ipv4(), Type::stream(), Some(Protocol::tcp())) {
+        Ok(socket) => socket,
+        Err(err) => return format!("unknown ({})", err),
+    };
+    let one: libc::c_int = 1;
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_IP,
+            libc::IP_TRANSPARENT,
+            &one as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if res == 0 {
+        "supported".to_string()
+    } else {
+        format!("unsupported ({})", std::io::Error::last_os_error())
+    }
+}
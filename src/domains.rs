@@ -0,0 +1,153 @@
+use crate::config::Config;
+use async_std::io;
+use std::collections::HashSet;
+
+pub struct DomainList {
+    exact: HashSet<String>,
+    // `*.ads.example` is stored as `ads.example` and matches names below it, not itself
+    wildcards: HashSet<String>,
+}
+
+impl DomainList {
+    pub(crate) fn load(path: &str) -> io::Result<DomainList> {
+        Ok(DomainList::parse(&std::fs::read_to_string(path)?))
+    }
+
+    // One domain or `*.suffix` per line; blank lines and `#` comments are skipped
+    fn parse(text: &str) -> DomainList {
+        let mut list = DomainList {
+            exact: HashSet::new(),
+            wildcards: HashSet::new(),
+        };
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let name = normalize(line);
+            match name.strip_prefix("*.") {
+                Some(suffix) => list.wildcards.insert(suffix.to_string()),
+                None => list.exact.insert(name),
+            };
+        }
+        list
+    }
+
+    fn matches(&self, domain: &str) -> bool {
+        if self.exact.contains(domain) {
+            return true;
+        }
+        domain
+            .match_indices('.')
+            .any(|(i, _)| self.wildcards.contains(&domain[i + 1..]))
+    }
+}
+
+// Checked before a name is resolved, so blocked names are never looked up
+pub(crate) fn allowed(domain: &str, config: &Config) -> bool {
+    if config.reject_single_label && is_single_label(domain) {
+        return false;
+    }
+    let domain = normalize(domain);
+    if let Some(allow) = &config.domain_allowlist {
+        if !allow.matches(&domain) {
+            return false;
+        }
+    }
+    match &config.domain_blocklist {
+        Some(block) => !block.matches(&domain),
+        None => true,
+    }
+}
+
+// A bare hostname; a trailing dot marks a name as complete even with one label
+fn is_single_label(domain: &str) -> bool {
+    !domain.contains('.')
+}
+
+// The names to try for `domain`, in order: a single label with each `--search-domain`
+// appended, then as is, the way resolv.conf's search list works
+pub(crate) fn search_names(domain: &str, config: &Config) -> Vec<String> {
+    let mut names = vec![];
+    if is_single_label(domain) {
+        names.extend(
+            config
+                .search_domains
+                .iter()
+                .map(|search| format!("{}.{}", domain, search)),
+        );
+    }
+    names.push(domain.to_string());
+    names
+}
+
+fn normalize(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(text: &str) -> Option<DomainList> {
+        Some(DomainList::parse(text))
+    }
+
+    #[test]
+    fn wildcards_cover_names_below_only() {
+        let config = Config {
+            domain_blocklist: list("# ads\nTracker.Example.\n*.ads.example  # and below\n\n"),
+            ..Config::default()
+        };
+        assert!(!allowed("tracker.example", &config));
+        assert!(!allowed("TRACKER.example.", &config));
+        assert!(allowed("cdn.tracker.example", &config));
+        assert!(!allowed("x.ads.example", &config));
+        assert!(!allowed("a.b.ads.example", &config));
+        assert!(allowed("ads.example", &config));
+        assert!(allowed("notads.example", &config));
+    }
+
+    #[test]
+    fn blocklist_overrides_allowlist() {
+        let config = Config {
+            domain_allowlist: list("example.com\n*.example.com"),
+            domain_blocklist: list("bad.example.com"),
+            ..Config::default()
+        };
+        assert!(allowed("example.com", &config));
+        assert!(allowed("www.example.com", &config));
+        assert!(!allowed("bad.example.com", &config));
+        assert!(!allowed("example.org", &config));
+    }
+
+    #[test]
+    fn search_domains_come_first() {
+        let config = Config {
+            search_domains: vec!["corp.example".to_string(), "example".to_string()],
+            ..Config::default()
+        };
+        assert_eq!(
+            search_names("intranet", &config),
+            ["intranet.corp.example", "intranet.example", "intranet"]
+        );
+        assert_eq!(search_names("www.example", &config), ["www.example"]);
+        assert_eq!(search_names("intranet", &Config::default()), ["intranet"]);
+    }
+
+    #[test]
+    fn single_labels_only_through_search_domains() {
+        let config = Config {
+            search_domains: vec!["corp.example".to_string()],
+            reject_single_label: true,
+            ..Config::default()
+        };
+        let names = search_names("intranet", &config)
+            .into_iter()
+            .filter(|name| allowed(name, &config))
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["intranet.corp.example"]);
+        assert!(allowed("localhost.", &config));
+    }
+}
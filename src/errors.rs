@@ -1,11 +1,23 @@
+use crate::config::ErrorMode;
+use std::net::{IpAddr, SocketAddr};
+
 #[derive(Debug)]
 pub enum Socks5Error {
     UnsupportedVersion,
     UnexpectedEOF,
+    #[allow(dead_code)]
     ExtraDataRead,
     UnsupportedCommand,
     UnrecognizedAddrType,
     ParseAddrError,
+    ConnectionNotAllowed,
+    // What was read before giving up, so it can be replayed to a decoy
+    BadPreamble(Vec<u8>),
+    NotSocks(Vec<u8>),
+    NoAcceptableMethod,
+    AuthFailed(String),
+    UserBound(String),
+    InvalidConfig(String),
     IOError(std::io::Error),
 }
 
@@ -24,9 +36,156 @@ impl std::fmt::Display for Socks5Error {
             Socks5Error::UnsupportedCommand => "Unsupported command".to_string(),
             Socks5Error::UnrecognizedAddrType => "Unrecognized target address type".to_string(),
             Socks5Error::ParseAddrError => "Parse address error".to_string(),
+            Socks5Error::ConnectionNotAllowed => "Connection not allowed".to_string(),
+            Socks5Error::BadPreamble(_) => "Missing or wrong preamble".to_string(),
+            Socks5Error::NotSocks(_) => "Not a socks5 greeting".to_string(),
+            Socks5Error::NoAcceptableMethod => "No acceptable auth method".to_string(),
+            Socks5Error::AuthFailed(user) => format!("Authentication failed for {}", user),
+            Socks5Error::UserBound(user) => format!("User {} is in use from another address", user),
+            Socks5Error::InvalidConfig(msg) => format!("Invalid config: {}", msg),
             Socks5Error::IOError(err) => err.to_string(),
         };
         write!(f, "[Err] {}", msg)?;
         Ok(())
     }
 }
+
+impl Socks5Error {
+    // A stable name for the kind of error, used to count them
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Socks5Error::UnsupportedVersion => "unsupported_version",
+            Socks5Error::UnexpectedEOF => "unexpected_eof",
+            Socks5Error::ExtraDataRead => "extra_data",
+            Socks5Error::UnsupportedCommand => "unsupported_command",
+            Socks5Error::UnrecognizedAddrType => "unrecognized_addr_type",
+            Socks5Error::ParseAddrError => "parse_addr",
+            Socks5Error::ConnectionNotAllowed => "not_allowed",
+            Socks5Error::BadPreamble(_) => "bad_preamble",
+            Socks5Error::NotSocks(_) => "not_socks",
+            Socks5Error::NoAcceptableMethod => "no_acceptable_method",
+            Socks5Error::AuthFailed(_) => "auth_failed",
+            Socks5Error::UserBound(_) => "user_bound",
+            Socks5Error::InvalidConfig(_) => "invalid_config",
+            Socks5Error::IOError(_) => "io",
+        }
+    }
+}
+
+// Where in a connection's life a failure happened
+#[derive(Debug, Clone, Copy)]
+pub enum Phase {
+    Handshake,
+    Auth,
+    Resolve,
+    Connect,
+    Reply,
+    Relay,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let phase = match self {
+            Phase::Handshake => "handshake",
+            Phase::Auth => "auth",
+            Phase::Resolve => "resolve",
+            Phase::Connect => "connect",
+            Phase::Reply => "reply",
+            Phase::Relay => "relay",
+        };
+        write!(f, "{}", phase)
+    }
+}
+
+// What the client spoke, so replies go out in the same protocol
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Socks5,
+    Socks4,
+    Http,
+}
+
+// What is known about a connection so far, filled in as the handshake progresses
+#[derive(Debug)]
+pub struct ConnContext {
+    pub id: u64,
+    pub client: String,
+    pub user: Option<String>,
+    // Proxies the connection was chained through before reaching this one
+    pub hops: u32,
+    pub target: Option<String>,
+    pub resolved: Vec<IpAddr>,
+    // Both ends of the client connection, when it came in over TCP
+    pub local: Option<SocketAddr>,
+    pub peer: Option<SocketAddr>,
+    // Set once the client has its success reply, after which failures can't be reported in SOCKS5
+    pub replied: bool,
+    // Overrides `--on-error`, set by the exit policy rule that denied the connection
+    pub error_mode: Option<ErrorMode>,
+    pub protocol: Protocol,
+    // Bytes relayed each way, once the tunnel is done
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl ConnContext {
+    pub fn new(id: u64, client: String) -> Self {
+        ConnContext {
+            id,
+            client,
+            user: None,
+            hops: 0,
+            target: None,
+            resolved: vec![],
+            local: None,
+            peer: None,
+            replied: false,
+            error_mode: None,
+            protocol: Protocol::Socks5,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    pub fn fail(self, phase: Phase, err: impl Into<Socks5Error>) -> ConnError {
+        ConnError {
+            ctx: self,
+            phase,
+            err: err.into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnError {
+    pub ctx: ConnContext,
+    pub phase: Phase,
+    pub err: Socks5Error,
+}
+
+impl std::fmt::Display for ConnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (#{} client {}",
+            self.err, self.ctx.id, self.ctx.client
+        )?;
+        if let Some(user) = &self.ctx.user {
+            write!(f, " user {}", user)?;
+        }
+        if let Some(target) = &self.ctx.target {
+            write!(f, ", target {}", target)?;
+        }
+        if !self.ctx.resolved.is_empty() {
+            let ips = self
+                .ctx
+                .resolved
+                .iter()
+                .map(|ip| ip.to_string())
+                .collect::<Vec<_>>()
+                .join(" ");
+            write!(f, " [{}]", ips)?;
+        }
+        write!(f, ", during {})", self.phase)
+    }
+}
@@ -0,0 +1,82 @@
+use async_std::io;
+use std::os::unix::io::RawFd;
+
+// <linux/filter.h>
+#[repr(C)]
+pub(crate) struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *mut SockFilter,
+}
+
+// Loads a classic BPF program in `tcpdump -ddd` format: the instruction count on the
+// first line, then one `code jt jf k` quadruple per line. On a TCP socket the packet
+// data starts at the TCP header, so the IP header has to be reached via SKF_NET_OFF.
+pub(crate) fn load(path: &str) -> io::Result<Vec<SockFilter>> {
+    let invalid =
+        |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, msg));
+
+    let text = std::fs::read_to_string(path)?;
+    let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+
+    let count = lines
+        .next()
+        .and_then(|l| l.parse::<usize>().ok())
+        .ok_or_else(|| invalid("missing instruction count"))?;
+
+    let mut prog = Vec::with_capacity(count);
+    for line in lines {
+        let fields = line
+            .split_whitespace()
+            .map(|f| f.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid("malformed instruction"))?;
+
+        match fields.as_slice() {
+            &[code, jt, jf, k] if code <= 0xffff && jt <= 0xff && jf <= 0xff => {
+                prog.push(SockFilter {
+                    code: code as u16,
+                    jt: jt as u8,
+                    jf: jf as u8,
+                    k,
+                })
+            }
+            _ => return Err(invalid("malformed instruction")),
+        }
+    }
+
+    if prog.len() != count || count == 0 || count > u16::MAX as usize {
+        return Err(invalid("instruction count mismatch"));
+    }
+    Ok(prog)
+}
+
+pub(crate) fn attach(fd: RawFd, prog: &mut [SockFilter]) -> io::Result<()> {
+    let fprog = SockFprog {
+        len: prog.len() as u16,
+        filter: prog.as_mut_ptr(),
+    };
+
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_FILTER,
+            &fprog as *const SockFprog as *const libc::c_void,
+            std::mem::size_of::<SockFprog>() as libc::socklen_t,
+        )
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
@@ -0,0 +1,64 @@
+use async_std::io;
+use std::net::IpAddr;
+
+struct Range {
+    start: IpAddr,
+    end: IpAddr,
+    country: String,
+    asn: u32,
+}
+
+pub struct GeoIp {
+    ranges: Vec<Range>,
+}
+
+impl GeoIp {
+    // Loads an ip2asn-style TSV table: `range_start range_end AS_number country_code ...`,
+    // one range per line. Unrouted ranges (AS 0) are skipped.
+    pub(crate) fn load(path: &str) -> io::Result<GeoIp> {
+        let invalid = |lineno: usize| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}:{}: malformed range", path, lineno + 1),
+            )
+        };
+
+        let text = std::fs::read_to_string(path)?;
+        let mut ranges = vec![];
+        for (lineno, line) in text.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let mut fields = line.split('\t');
+            let mut next = || fields.next().ok_or_else(|| invalid(lineno));
+            let start = next()?.parse::<IpAddr>().map_err(|_| invalid(lineno))?;
+            let end = next()?.parse::<IpAddr>().map_err(|_| invalid(lineno))?;
+            let asn = next()?.parse::<u32>().map_err(|_| invalid(lineno))?;
+            let country = next()?.to_string();
+
+            if asn != 0 {
+                ranges.push(Range {
+                    start,
+                    end,
+                    country,
+                    asn,
+                });
+            }
+        }
+
+        ranges.sort_by_key(|r| r.start);
+        Ok(GeoIp { ranges })
+    }
+
+    // `CC ASn` for the range holding `ip`, if any
+    pub(crate) fn lookup(&self, ip: IpAddr) -> Option<String> {
+        let i = self.ranges.partition_point(|r| r.start <= ip);
+        let range = self.ranges.get(i.checked_sub(1)?)?;
+        if ip <= range.end {
+            Some(format!("{} AS{}", range.country, range.asn))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use crate::{
+    errors::Socks5Error,
+    protocol::{
+        self, Decoded, Reply, Request, UserPass, AUTH_FAILURE, AUTH_SUCCESS, NO_ACCEPTABLE,
+        NO_AUTH, SOCKS_VERSION, USER_PASS, USER_PASS_VERSION,
+    },
+};
+use async_std::{
+    io::{Read, Write},
+    prelude::*,
+};
+
+// Both sides of the SOCKS5 handshake as state machines that never touch a socket: bytes
+// from the peer go in through `feed_bytes`, and what they amount to comes out. Bytes fed
+// ahead of time are kept, so a peer that pipelines its messages is handled the same as one
+// that waits for each answer.
+
+// The server side, from the greeting up to the request
+pub(crate) struct Server {
+    state: ServerState,
+    buf: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ServerState {
+    Greeting,
+    Auth,
+    Request,
+    // The last event has to be answered before anything else is decoded
+    Waiting,
+    Done,
+}
+
+pub(crate) enum ServerEvent {
+    // At least this many more bytes from the client are needed; 0 while an event is still
+    // unanswered or once the handshake is over
+    NeedMoreData(usize),
+    // The offered methods; answer with `choose_method`
+    Methods(Vec<u8>),
+    // Credentials to check; answer with `auth_result`
+    Auth(UserPass),
+    Request(Request),
+    Error(Socks5Error),
+}
+
+impl Server {
+    pub(crate) fn new() -> Self {
+        Server {
+            state: ServerState::Greeting,
+            buf: vec![],
+        }
+    }
+
+    pub(crate) fn feed_bytes(&mut self, data: &[u8]) -> ServerEvent {
+        self.buf.extend_from_slice(data);
+        let decoded = match self.state {
+            ServerState::Greeting => {
+                protocol::decode_greeting(&self.buf).map(|d| map(d, ServerEvent::Methods))
+            }
+            ServerState::Auth => UserPass::decode(&self.buf).map(|d| map(d, ServerEvent::Auth)),
+            ServerState::Request => {
+                Request::decode(&self.buf).map(|d| map(d, ServerEvent::Request))
+            }
+            ServerState::Waiting | ServerState::Done => return ServerEvent::NeedMoreData(0),
+        };
+
+        match decoded {
+            Ok(Decoded::Incomplete(more)) => ServerEvent::NeedMoreData(more),
+            Ok(Decoded::Complete(event, len)) => {
+                self.buf.drain(..len);
+                self.state = match event {
+                    ServerEvent::Request(_) => ServerState::Done,
+                    _ => ServerState::Waiting,
+                };
+                event
+            }
+            Err(err) => {
+                self.state = ServerState::Done;
+                ServerEvent::Error(err)
+            }
+        }
+    }
+
+    // The answer to the greeting. NO_ACCEPTABLE ends the handshake.
+    pub(crate) fn choose_method(&mut self, method: u8) -> [u8; 2] {
+        self.state = match method {
+            USER_PASS => ServerState::Auth,
+            NO_ACCEPTABLE => ServerState::Done,
+            _ => ServerState::Request,
+        };
+        [SOCKS_VERSION, method]
+    }
+
+    // The answer to the credentials. A failure ends the handshake.
+    pub(crate) fn auth_result(&mut self, success: bool) -> [u8; 2] {
+        if success {
+            self.state = ServerState::Request;
+            [USER_PASS_VERSION, AUTH_SUCCESS]
+        } else {
+            self.state = ServerState::Done;
+            [USER_PASS_VERSION, AUTH_FAILURE]
+        }
+    }
+}
+
+// The client side, from the greeting up to the reply
+pub(crate) struct Client {
+    state: ClientState,
+    buf: Vec<u8>,
+    username: Option<String>,
+    auth: Option<Vec<u8>>,
+    request: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ClientState {
+    Method,
+    Auth,
+    Reply,
+    Done,
+}
+
+pub(crate) enum ClientEvent {
+    // At least this many more bytes from the server are needed; 0 once the reply is in
+    NeedMoreData(usize),
+    // Bytes for the server before anything more will come back
+    Send(Vec<u8>),
+    Reply(Reply),
+    Error(Socks5Error),
+}
+
+impl Client {
+    // Everything is encoded up front, so a request that can't be sent fails before any IO
+    pub(crate) fn new(request: &Request, auth: Option<&UserPass>) -> Result<Self, Socks5Error> {
+        Ok(Client {
+            state: ClientState::Method,
+            buf: vec![],
+            username: auth.map(|auth| auth.username.clone()),
+            auth: auth.map(|auth| auth.encode()).transpose()?,
+            request: request.encode()?,
+        })
+    }
+
+    // What opens the handshake, offering username/password only when there's some to give
+    pub(crate) fn greeting(&self) -> [u8; 3] {
+        let method = if self.auth.is_some() {
+            USER_PASS
+        } else {
+            NO_AUTH
+        };
+        [SOCKS_VERSION, 0x1, method]
+    }
+
+    pub(crate) fn feed_bytes(&mut self, data: &[u8]) -> ClientEvent {
+        self.buf.extend_from_slice(data);
+        match self.step() {
+            Ok(Some((event, len))) => {
+                self.buf.drain(..len);
+                event
+            }
+            Ok(None) => ClientEvent::NeedMoreData(0),
+            Err(err) => {
+                self.state = ClientState::Done;
+                ClientEvent::Error(err)
+            }
+        }
+    }
+
+    fn step(&mut self) -> Result<Option<(ClientEvent, usize)>, Socks5Error> {
+        let step = match self.state {
+            ClientState::Method => match protocol::decode_method(&self.buf)? {
+                Decoded::Incomplete(more) => (ClientEvent::NeedMoreData(more), 0),
+                Decoded::Complete(method, len) => match (method, self.auth.take()) {
+                    (NO_AUTH, _) => {
+                        self.state = ClientState::Reply;
+                        (ClientEvent::Send(self.request.clone()), len)
+                    }
+                    (USER_PASS, Some(auth)) => {
+                        self.state = ClientState::Auth;
+                        (ClientEvent::Send(auth), len)
+                    }
+                    _ => return Err(Socks5Error::NoAcceptableMethod),
+                },
+            },
+            ClientState::Auth => match protocol::decode_auth_status(&self.buf) {
+                Decoded::Incomplete(more) => (ClientEvent::NeedMoreData(more), 0),
+                Decoded::Complete(true, len) => {
+                    self.state = ClientState::Reply;
+                    (ClientEvent::Send(self.request.clone()), len)
+                }
+                Decoded::Complete(false, _) => {
+                    let username = self.username.take().unwrap_or_default();
+                    return Err(Socks5Error::AuthFailed(username));
+                }
+            },
+            ClientState::Reply => match Reply::decode(&self.buf)? {
+                Decoded::Incomplete(more) => (ClientEvent::NeedMoreData(more), 0),
+                Decoded::Complete(reply, len) => {
+                    self.state = ClientState::Done;
+                    (ClientEvent::Reply(reply), len)
+                }
+            },
+            ClientState::Done => return Ok(None),
+        };
+        Ok(Some(step))
+    }
+}
+
+// Reads exactly what the server side asks for, after `data` already read, until it has
+// something to answer. Nothing past the request is read, so it's left for the relay.
+pub(crate) async fn next_event<S: Read + Unpin>(
+    stream: &mut S,
+    server: &mut Server,
+    data: &[u8],
+) -> ServerEvent {
+    let mut event = server.feed_bytes(data);
+    while let ServerEvent::NeedMoreData(more) = event {
+        if more == 0 {
+            return ServerEvent::Error(Socks5Error::UnexpectedEOF);
+        }
+        let mut buf = vec![0u8; more];
+        if let Err(err) = stream.read_exact(&mut buf).await {
+            return ServerEvent::Error(err.into());
+        }
+        event = server.feed_bytes(&buf);
+    }
+    event
+}
+
+// The whole client side over `stream`, returning the server's reply
+pub(crate) async fn run_client<S: Read + Write + Unpin>(
+    stream: &mut S,
+    mut client: Client,
+) -> Result<Reply, Socks5Error> {
+    stream.write_all(&client.greeting()).await?;
+    let mut buf = vec![];
+    loop {
+        match client.feed_bytes(&buf) {
+            ClientEvent::NeedMoreData(0) => return Err(Socks5Error::UnexpectedEOF),
+            ClientEvent::NeedMoreData(more) => {
+                buf = vec![0u8; more];
+                stream.read_exact(&mut buf).await?;
+            }
+            ClientEvent::Send(data) => {
+                buf.clear();
+                stream.write_all(&data).await?;
+            }
+            ClientEvent::Reply(reply) => return Ok(reply),
+            ClientEvent::Error(err) => return Err(err),
+        }
+    }
+}
+
+fn map<T>(decoded: Decoded<T>, event: impl FnOnce(T) -> ServerEvent) -> Decoded<ServerEvent> {
+    match decoded {
+        Decoded::Incomplete(more) => Decoded::Incomplete(more),
+        Decoded::Complete(msg, len) => Decoded::Complete(event(msg), len),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{address::Address, protocol::CMD_CONNECT};
+
+    const GREETING: [u8; 3] = [SOCKS_VERSION, 1, USER_PASS];
+
+    fn auth() -> Vec<u8> {
+        UserPass {
+            username: "bob".to_string(),
+            password: b"secret".to_vec(),
+        }
+        .encode()
+        .unwrap()
+    }
+
+    fn request() -> Vec<u8> {
+        Request {
+            cmd: CMD_CONNECT,
+            target: Address::Domain("example.com".to_string(), 443),
+        }
+        .encode()
+        .unwrap()
+    }
+
+    // Feeds `data` one byte at a time until something other than NeedMoreData comes out
+    fn feed_slowly(server: &mut Server, data: &[u8]) -> ServerEvent {
+        for (i, byte) in data.iter().enumerate() {
+            match server.feed_bytes(&[*byte]) {
+                ServerEvent::NeedMoreData(more) => assert!(more > 0 && i + more < data.len()),
+                event => {
+                    assert_eq!(i, data.len() - 1, "event before the message was complete");
+                    return event;
+                }
+            }
+        }
+        panic!("no event after {} bytes", data.len());
+    }
+
+    fn assert_request(event: ServerEvent) {
+        match event {
+            ServerEvent::Request(request) => {
+                assert_eq!(request.cmd, CMD_CONNECT);
+                assert_eq!(
+                    request.target,
+                    Address::Domain("example.com".to_string(), 443)
+                );
+            }
+            _ => panic!("expected the request"),
+        }
+    }
+
+    #[test]
+    fn server_takes_a_byte_at_a_time() {
+        let mut server = Server::new();
+        match feed_slowly(&mut server, &GREETING) {
+            ServerEvent::Methods(methods) => assert_eq!(methods, [USER_PASS]),
+            _ => panic!("expected the methods"),
+        }
+        assert_eq!(server.choose_method(USER_PASS), [SOCKS_VERSION, USER_PASS]);
+
+        match feed_slowly(&mut server, &auth()) {
+            ServerEvent::Auth(auth) => {
+                assert_eq!(auth.username, "bob");
+                assert_eq!(auth.password, b"secret");
+            }
+            _ => panic!("expected the credentials"),
+        }
+        assert_eq!(server.auth_result(true), [USER_PASS_VERSION, AUTH_SUCCESS]);
+
+        assert_request(feed_slowly(&mut server, &request()));
+        assert!(matches!(
+            server.feed_bytes(&[]),
+            ServerEvent::NeedMoreData(0)
+        ));
+    }
+
+    #[test]
+    fn server_takes_a_pipelined_handshake() {
+        let mut data = GREETING.to_vec();
+        data.extend(auth());
+        data.extend(request());
+
+        let mut server = Server::new();
+        assert!(matches!(server.feed_bytes(&data), ServerEvent::Methods(_)));
+        // Nothing more comes out until the methods are answered
+        assert!(matches!(
+            server.feed_bytes(&[]),
+            ServerEvent::NeedMoreData(0)
+        ));
+
+        server.choose_method(USER_PASS);
+        assert!(matches!(server.feed_bytes(&[]), ServerEvent::Auth(_)));
+        server.auth_result(true);
+        assert_request(server.feed_bytes(&[]));
+    }
+
+    #[test]
+    fn server_stops_after_failed_auth() {
+        let mut data = GREETING.to_vec();
+        data.extend(auth());
+        data.extend(request());
+
+        let mut server = Server::new();
+        server.feed_bytes(&data);
+        server.choose_method(USER_PASS);
+        server.feed_bytes(&[]);
+        assert_eq!(server.auth_result(false), [USER_PASS_VERSION, AUTH_FAILURE]);
+        assert!(matches!(
+            server.feed_bytes(&[]),
+            ServerEvent::NeedMoreData(0)
+        ));
+    }
+
+    #[test]
+    fn server_rejects_other_protocols() {
+        let mut server = Server::new();
+        match server.feed_bytes(b"GET / HTTP/1.1\r\n") {
+            ServerEvent::Error(Socks5Error::NotSocks(head)) => assert_eq!(head, b"GE"),
+            _ => panic!("expected NotSocks"),
+        }
+    }
+}
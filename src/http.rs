@@ -0,0 +1,358 @@
+use crate::address::Address;
+use crate::config::Config;
+use crate::errors::Socks5Error;
+use crate::protocol::{
+    RESP_ADDR_NOT_SUPPORTED, RESP_CMD_NOT_SUPPORTED, RESP_NOT_ALLOWED, RESP_TTL_EXPIRED,
+};
+use async_std::{
+    io::{Read, Write},
+    net::IpAddr,
+    prelude::*,
+};
+
+// What an HTTP CONNECT request starts with, as far as the two bytes sniffed for the protocol go
+pub(crate) const CONNECT_HEAD: [u8; 2] = *b"CO";
+// Longest request head read before giving up on the blank line ending it
+const MAX_HEAD: usize = 8192;
+
+// The rest of an HTTP CONNECT request after the sniffed `head`, returning the target and,
+// when credentials are required, the user that authenticated with Proxy-Authorization
+pub(crate) async fn request<S: Read + Write + Unpin>(
+    stream: &mut S,
+    head: [u8; 2],
+    peer: Option<IpAddr>,
+    config: &Config,
+) -> Result<(Address, Option<String>), Socks5Error> {
+    let mut buf = head.to_vec();
+    let mut byte = [0u8; 1];
+    while !buf.ends_with(b"\r\n\r\n") {
+        if buf.len() == MAX_HEAD {
+            return Err(Socks5Error::ParseAddrError);
+        }
+        stream.read_exact(&mut byte).await?;
+        buf.push(byte[0]);
+    }
+    let text = String::from_utf8(buf).map_err(|_| Socks5Error::ParseAddrError)?;
+    let mut lines = text.split("\r\n");
+
+    let mut request_line = lines.next().unwrap_or("").split(' ');
+    let (method, authority) = match (request_line.next(), request_line.next()) {
+        (Some(method), Some(authority)) => (method, authority),
+        _ => return Err(Socks5Error::ParseAddrError),
+    };
+    if method != "CONNECT" {
+        return Err(Socks5Error::UnsupportedCommand);
+    }
+    let target = parse_authority(authority).ok_or(Socks5Error::ParseAddrError)?;
+
+    if !config.authenticator.wants_user_pass() {
+        return Ok((target, None));
+    }
+    let credentials = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("proxy-authorization"))
+        .and_then(|(_, value)| basic_credentials(value.trim()));
+    let (username, password) = match credentials {
+        Some(credentials) => credentials,
+        None => {
+            reply_auth_required(stream).await?;
+            return Err(Socks5Error::NoAcceptableMethod);
+        }
+    };
+    if let Err(err) = crate::server::check_credentials(&username, &password, peer, config).await {
+        reply_auth_required(stream).await?;
+        return Err(err);
+    }
+    Ok((target, Some(username)))
+}
+
+fn parse_authority(authority: &str) -> Option<Address> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some(match host.parse::<IpAddr>() {
+        Ok(ip) => Address::from_ip(ip, port),
+        Err(_) => Address::Domain(host.to_string(), port),
+    })
+}
+
+// `Basic base64(user:password)`
+fn basic_credentials(value: &str) -> Option<(String, Vec<u8>)> {
+    let (scheme, encoded) = value.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = base64_decode(encoded.trim())?;
+    let colon = decoded.iter().position(|&b| b == b':')?;
+    let username = String::from_utf8_lossy(&decoded[..colon]).into_owned();
+    Some((username, decoded[colon + 1..].to_vec()))
+}
+
+// Padding is optional, but where there is some it has to fill out the last group of four
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    let data = s.trim_end_matches('=');
+    let padding = s.len() - data.len();
+    if padding > 2 || (padding > 0 && !s.len().is_multiple_of(4)) || data.len() % 4 == 1 {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in data.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+// The client side of CONNECT, for an `http://` upstream: asks it for a tunnel to `target`,
+// with Basic credentials when there are any
+pub(crate) async fn connect<S: Read + Write + Unpin>(
+    stream: &mut S,
+    target: &Address,
+    auth: Option<(&str, &str)>,
+) -> Result<(), Socks5Error> {
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+    if let Some((user, pass)) = auth {
+        let credentials = base64_encode(format!("{}:{}", user, pass).as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", credentials));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Only the head: what follows it is already the tunnel
+    let mut buf = vec![];
+    let mut byte = [0u8; 1];
+    while !buf.ends_with(b"\r\n\r\n") {
+        if buf.len() == MAX_HEAD {
+            return Err(Socks5Error::ParseAddrError);
+        }
+        stream.read_exact(&mut byte).await?;
+        buf.push(byte[0]);
+    }
+    let status = std::str::from_utf8(&buf)
+        .ok()
+        .and_then(|head| head.split(' ').nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or(Socks5Error::ParseAddrError)?;
+    match status {
+        200..=299 => Ok(()),
+        407 => {
+            crate::log::warn(format_args!("the upstream turned down its credentials"));
+            Err(Socks5Error::ConnectionNotAllowed)
+        }
+        403 => Err(Socks5Error::ConnectionNotAllowed),
+        504 => Err(std::io::Error::from(std::io::ErrorKind::TimedOut).into()),
+        _ => Err(
+            std::io::Error::other(format!("upstream failed with HTTP status {}", status)).into(),
+        ),
+    }
+}
+
+async fn reply_auth_required<S: Write + Unpin>(stream: &mut S) -> Result<(), std::io::Error> {
+    stream
+        .write_all(
+            b"HTTP/1.1 407 Proxy Authentication Required\r\n\
+              Proxy-Authenticate: Basic realm=\"proxy\"\r\n\
+              Content-Length: 0\r\n\r\n",
+        )
+        .await
+}
+
+pub(crate) async fn reply_success<S: Write + Unpin>(stream: &mut S) -> Result<(), std::io::Error> {
+    stream
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .await
+}
+
+// The status line standing in for a SOCKS5 reply code
+pub(crate) async fn reply_failure<S: Write + Unpin>(
+    stream: &mut S,
+    rep: u8,
+) -> Result<(), std::io::Error> {
+    let status = match rep {
+        RESP_NOT_ALLOWED => "403 Forbidden",
+        RESP_TTL_EXPIRED => "504 Gateway Timeout",
+        RESP_CMD_NOT_SUPPORTED => "405 Method Not Allowed",
+        RESP_ADDR_NOT_SUPPORTED => "400 Bad Request",
+        _ => "502 Bad Gateway",
+    };
+    let reply = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+    stream.write_all(reply.as_bytes()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::StaticUserPass;
+    use async_std::task;
+    use futures::io::Cursor;
+    use std::sync::Arc;
+
+    #[test]
+    fn base64_round_trip() {
+        for text in ["", "a", "ab", "abc", "abcd", "alice:s3cr\u{e9}t"] {
+            let encoded = base64_encode(text.as_bytes());
+            assert_eq!(
+                base64_decode(&encoded).unwrap(),
+                text.as_bytes(),
+                "{}",
+                encoded
+            );
+        }
+        assert_eq!(base64_encode(b"alice:secret"), "YWxpY2U6c2VjcmV0");
+        // Padding may be left off
+        assert_eq!(base64_decode("YQ").unwrap(), b"a");
+    }
+
+    #[test]
+    fn base64_malformed() {
+        for encoded in [
+            "Y",
+            "YQ=",
+            "YQ=x",
+            "Y===",
+            "YWJj=",
+            "YWJjZA===",
+            "YW=J",
+            "YW!j",
+        ] {
+            assert_eq!(base64_decode(encoded), None, "{}", encoded);
+        }
+    }
+
+    #[test]
+    fn authorities() {
+        let parse = |s: &str| parse_authority(s);
+        assert_eq!(
+            parse("example.com:443"),
+            Some(Address::Domain("example.com".to_string(), 443))
+        );
+        assert_eq!(
+            parse("[2001:db8::1]:8080"),
+            Some(Address::Ip("[2001:db8::1]:8080".parse().unwrap()))
+        );
+        assert_eq!(
+            parse("192.0.2.1:80"),
+            Some(Address::Ip("192.0.2.1:80".parse().unwrap()))
+        );
+        assert_eq!(parse(":80"), None);
+        assert_eq!(parse("example.com"), None);
+        assert_eq!(parse("example.com:http"), None);
+    }
+
+    #[test]
+    fn basic_auth_header() {
+        assert_eq!(
+            basic_credentials("Basic YWxpY2U6c2VjcmV0"),
+            Some(("alice".to_string(), b"secret".to_vec()))
+        );
+        // Only the first colon splits, the password may have more
+        assert_eq!(
+            basic_credentials("basic YTpiOmM="),
+            Some(("a".to_string(), b"b:c".to_vec()))
+        );
+        assert_eq!(basic_credentials("Bearer YWxpY2U6c2VjcmV0"), None);
+        assert_eq!(basic_credentials("Basic YWxpY2U="), None);
+        assert_eq!(basic_credentials("Basic YWxpY2U6c2VjcmV0="), None);
+    }
+
+    // Runs `head` through the parser, returning what it made of it and what was written back
+    fn connect_request(
+        head: &str,
+        config: &Config,
+    ) -> (Result<(Address, Option<String>), Socks5Error>, String) {
+        task::block_on(async {
+            let rest = head.as_bytes()[2..].to_vec();
+            let len = rest.len();
+            let mut stream = Cursor::new(rest);
+            let sniffed = [head.as_bytes()[0], head.as_bytes()[1]];
+            let res = request(&mut stream, sniffed, None, config).await;
+            let written = String::from_utf8(stream.into_inner()[len..].to_vec()).unwrap();
+            (res, written)
+        })
+    }
+
+    #[test]
+    fn connect_requests() {
+        let config = Config::default();
+        let (res, _) = connect_request(
+            "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n",
+            &config,
+        );
+        assert_eq!(
+            res.unwrap(),
+            (Address::Domain("example.com".to_string(), 443), None)
+        );
+
+        let (res, _) = connect_request("GET http://example.com/ HTTP/1.1\r\n\r\n", &config);
+        assert!(matches!(res, Err(Socks5Error::UnsupportedCommand)));
+        let (res, _) = connect_request("CONNECT example.com HTTP/1.1\r\n\r\n", &config);
+        assert!(matches!(res, Err(Socks5Error::ParseAddrError)));
+        let (res, _) = connect_request("CONNECT\r\n\r\n", &config);
+        assert!(matches!(res, Err(Socks5Error::ParseAddrError)));
+    }
+
+    #[test]
+    fn proxy_authorization() {
+        let config = Config {
+            authenticator: Arc::new(StaticUserPass::new(vec![(
+                "alice".to_string(),
+                "secret".to_string(),
+            )])),
+            ..Config::default()
+        };
+
+        let (res, written) = connect_request(
+            "CONNECT example.com:443 HTTP/1.1\r\nproxy-AUTHORIZATION:  Basic YWxpY2U6c2VjcmV0 \r\n\r\n",
+            &config,
+        );
+        assert_eq!(res.unwrap().1.as_deref(), Some("alice"));
+        assert!(written.is_empty());
+
+        let (res, written) = connect_request("CONNECT example.com:443 HTTP/1.1\r\n\r\n", &config);
+        assert!(matches!(res, Err(Socks5Error::NoAcceptableMethod)));
+        assert!(written.starts_with("HTTP/1.1 407 "));
+
+        let (res, written) = connect_request(
+            "CONNECT example.com:443 HTTP/1.1\r\nProxy-Authorization: Basic YWxpY2U6d3Jvbmc=\r\n\r\n",
+            &config,
+        );
+        assert!(matches!(res, Err(Socks5Error::AuthFailed(_))));
+        assert!(written.starts_with("HTTP/1.1 407 "));
+    }
+}
@@ -1,12 +1,116 @@
 use crate::errors::Socks5Error;
-use async_std::io::ReadExt;
-use std::convert::TryInto;
+use async_std::io::{self, prelude::*, ReadExt};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
-pub(crate) fn try_into_wrapper<F, T>(from: F) -> Result<T, <F as TryInto<T>>::Error>
-where
-    F: TryInto<T>,
-{
-    from.try_into()
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(0);
+
+pub(crate) fn next_conn_id() -> u64 {
+    NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// What the relay needs from a client connection, whichever listener it came from
+#[cfg(unix)]
+pub(crate) trait Stream: Read + Write + Unpin + Send + AsRawFd + 'static {}
+
+#[cfg(unix)]
+impl<T: Read + Write + Unpin + Send + AsRawFd + 'static> Stream for T {}
+
+#[cfg(not(unix))]
+pub(crate) trait Stream: Read + Write + Unpin + Send + 'static {}
+
+#[cfg(not(unix))]
+impl<T: Read + Write + Unpin + Send + 'static> Stream for T {}
+
+// Hex-dumps everything read from and written to the client while it's wrapped,
+// stamped with a per-connection id and the time since the connection was accepted
+pub(crate) struct Traced<S> {
+    inner: S,
+    trace: Option<(u64, Instant)>,
+}
+
+impl<S> Traced<S> {
+    pub(crate) fn new(inner: S, id: u64, enabled: bool) -> Self {
+        let trace = if enabled {
+            Some((id, Instant::now()))
+        } else {
+            None
+        };
+        Traced { inner, trace }
+    }
+
+    pub(crate) fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub(crate) fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn dump(&self, direction: &str, bytes: &[u8]) {
+        if let Some((id, start)) = self.trace {
+            let hex = bytes
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            crate::log::trace(
+                id,
+                format_args!(
+                    "+{:.3}ms {} {}",
+                    start.elapsed().as_secs_f64() * 1000.0,
+                    direction,
+                    hex
+                ),
+            );
+        }
+    }
+}
+
+impl<S: Read + Unpin> Read for Traced<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.dump("client -> proxy", &buf[..n]);
+        }
+        res
+    }
+}
+
+impl<S: Write + Unpin> Write for Traced<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            self.dump("proxy -> client", &buf[..n]);
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+// Compares without an early exit so the timing doesn't leak how much of a secret matched
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 pub(crate) async fn _read_n_bytes(
@@ -14,7 +118,7 @@ pub(crate) async fn _read_n_bytes(
     buf: &mut [u8],
     count: usize,
 ) -> Result<usize, Socks5Error> {
-    if count <= 0 {
+    if count == 0 {
         return Ok(0);
     }
 
@@ -36,3 +140,314 @@ pub(crate) async fn _read_n_bytes(
         Err(Socks5Error::UnexpectedEOF)
     }
 }
+
+// How a relay may give up on or slow down a tunnel
+#[derive(Clone, Copy, Default)]
+pub(crate) struct CopyOptions<'a> {
+    pub stall_timeout: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub rate_limit: Option<u64>,
+    // The server's rate limit windows, which may pace the tunnel even without a `rate_limit`
+    pub schedule: Option<&'a crate::schedule::Schedule>,
+    // Where to count bytes as they go by, for `--tunnel-stats`
+    pub stats: Option<&'a crate::tunnels::TunnelStats>,
+}
+
+// When bytes last moved in either direction of a tunnel
+struct Activity {
+    start: Instant,
+    last_ms: AtomicU64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last_ms: AtomicU64::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.last_ms.store(now, Ordering::Relaxed);
+    }
+
+    fn idle(&self) -> Duration {
+        let last = Duration::from_millis(self.last_ms.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
+
+// Like `io::copy`, but gives up once a single write has been blocked for `stall_timeout`,
+// which an idle timeout can't catch since the reading side keeps making progress
+async fn copy_stall_timeout<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    opts: CopyOptions<'_>,
+    activity: &Activity,
+    counter: Option<&AtomicU64>,
+) -> io::Result<u64>
+where
+    R: Read + Unpin + ?Sized,
+    W: Write + Unpin + ?Sized,
+{
+    let paced = opts.rate_limit.is_some() || opts.schedule.is_some();
+    if opts.stall_timeout.is_none() && !paced && opts.idle_timeout.is_none() && counter.is_none() {
+        return io::copy(reader, writer).await;
+    }
+    let mut pacer = if paced {
+        Some(Pacer::new(opts.rate_limit, opts.schedule))
+    } else {
+        None
+    };
+
+    let mut buf = [0u8; 8192];
+    let mut total = 0;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(total);
+        }
+        activity.touch();
+        if let Some(counter) = counter {
+            counter.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        if let Some(pacer) = &mut pacer {
+            pacer.consume(n as u64).await;
+        }
+
+        let write = writer.write_all(&buf[..n]);
+        match opts.stall_timeout {
+            Some(stall_timeout) => io::timeout(stall_timeout, write).await.map_err(|err| {
+                if err.kind() == io::ErrorKind::TimedOut {
+                    io::Error::new(io::ErrorKind::TimedOut, "write stalled")
+                } else {
+                    err
+                }
+            })?,
+            None => write.await?,
+        }
+        total += n as u64;
+    }
+}
+
+// A token bucket holding up to one second's worth of bytes at the rate in force, which is
+// `default` unless a rate limit window says otherwise
+struct Pacer<'a> {
+    default: Option<u64>,
+    schedule: Option<&'a crate::schedule::Schedule>,
+    tokens: u64,
+    last: Instant,
+}
+
+impl<'a> Pacer<'a> {
+    fn new(default: Option<u64>, schedule: Option<&'a crate::schedule::Schedule>) -> Self {
+        Pacer {
+            default,
+            schedule,
+            // Starts full, whatever the rate turns out to be
+            tokens: u64::MAX,
+            last: Instant::now(),
+        }
+    }
+
+    async fn consume(&mut self, n: u64) {
+        let rate = match self.schedule.map_or(self.default, |s| s.rate(self.default)) {
+            Some(rate) => rate,
+            None => return,
+        };
+        // More than a second of refill is a full bucket anyway
+        let elapsed = self.last.elapsed().min(Duration::from_secs(1));
+        let refill = (elapsed.as_micros() as u64).saturating_mul(rate) / 1_000_000;
+        self.tokens = self.tokens.saturating_add(refill).min(rate);
+        self.last = Instant::now();
+
+        if self.tokens >= n {
+            self.tokens -= n;
+            return;
+        }
+        let deficit = n - self.tokens;
+        self.tokens = 0;
+        async_std::task::sleep(Duration::from_micros(
+            deficit.saturating_mul(1_000_000) / rate,
+        ))
+        .await;
+        self.last = Instant::now();
+    }
+}
+
+// Drives both directions of a tunnel in the calling task and returns (bytes_up, bytes_down).
+// Each direction half-closes its destination on EOF; an error in either one drops both
+// halves, closing the sockets, without waiting on the other
+pub(crate) async fn copy_bidirectional<A: Stream, B: Stream>(
+    a: A,
+    b: B,
+    opts: CopyOptions<'_>,
+) -> io::Result<(u64, u64)> {
+    #[cfg(unix)]
+    let (a_fd, b_fd) = (a.as_raw_fd(), b.as_raw_fd());
+    let (mut a_reader, mut a_writer) = futures::io::AsyncReadExt::split(a);
+    let (mut b_reader, mut b_writer) = futures::io::AsyncReadExt::split(b);
+    let activity = Activity::new();
+
+    let up = async {
+        let counter = opts.stats.map(|stats| &stats.up);
+        let n = copy_stall_timeout(&mut a_reader, &mut b_writer, opts, &activity, counter).await?;
+        futures::io::AsyncWriteExt::close(&mut b_writer).await?;
+        #[cfg(unix)]
+        let _ = shutdown_write(b_fd);
+        Ok::<_, io::Error>(n)
+    };
+    let down = async {
+        let counter = opts.stats.map(|stats| &stats.down);
+        let n = copy_stall_timeout(&mut b_reader, &mut a_writer, opts, &activity, counter).await?;
+        futures::io::AsyncWriteExt::close(&mut a_writer).await?;
+        #[cfg(unix)]
+        let _ = shutdown_write(a_fd);
+        Ok::<_, io::Error>(n)
+    };
+    let relay = async { futures::try_join!(up, down) };
+
+    let idle_timeout = match opts.idle_timeout {
+        Some(idle_timeout) => idle_timeout,
+        None => return relay.await,
+    };
+    let watchdog = async {
+        loop {
+            let idle = activity.idle();
+            if idle >= idle_timeout {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("idle for {}s", idle_timeout.as_secs()),
+                ));
+            }
+            async_std::task::sleep(idle_timeout - idle).await;
+        }
+    };
+    futures::pin_mut!(relay, watchdog);
+    match futures::future::select(relay, watchdog).await {
+        futures::future::Either::Left((res, _)) | futures::future::Either::Right((res, _)) => res,
+    }
+}
+
+// Loopback, private, link-local and other addresses that only make sense inside a network,
+// including IPv4 ones embedded by NAT64, under 64:ff9b::/96 or the configured `nat64_prefix`
+pub(crate) fn is_internal(ip: &IpAddr, nat64_prefix: Option<(Ipv6Addr, u8)>) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_multicast()
+                || ip.is_broadcast()
+                // "This network", RFC 1122
+                || a == 0
+                // Shared address space, RFC 6598
+                || (a == 100 && (b & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => {
+            let well_known = Ipv6Addr::new(0x64, 0xff9b, 0, 0, 0, 0, 0, 0);
+            let embedded = ip
+                .to_ipv4_mapped()
+                .or_else(|| crate::outbound::extract_nat64(ip, well_known, 96))
+                .or_else(|| {
+                    let (prefix, len) = nat64_prefix?;
+                    crate::outbound::extract_nat64(ip, prefix, len)
+                });
+            match embedded {
+                Some(v4) => is_internal(&IpAddr::V4(v4), None),
+                None => {
+                    let first = ip.segments()[0];
+                    ip.is_loopback()
+                        || ip.is_unspecified()
+                        || ip.is_multicast()
+                        // Unique local fc00::/7 and link-local fe80::/10
+                        || (first & 0xfe00) == 0xfc00
+                        || (first & 0xffc0) == 0xfe80
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn local_ips() -> Vec<IpAddr> {
+    let mut ips = vec![];
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return ips;
+    }
+
+    let mut cur = ifaddrs;
+    while !cur.is_null() {
+        let ifa = unsafe { &*cur };
+        if !ifa.ifa_addr.is_null() {
+            match unsafe { (*ifa.ifa_addr).sa_family } as libc::c_int {
+                libc::AF_INET => {
+                    let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                    ips.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(
+                        sin.sin_addr.s_addr,
+                    ))));
+                }
+                libc::AF_INET6 => {
+                    let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                    ips.push(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)));
+                }
+                _ => (),
+            }
+        }
+        cur = ifa.ifa_next;
+    }
+
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    ips
+}
+
+// `close` on async-std sockets only flushes, so the FIN has to be sent by hand
+#[cfg(unix)]
+fn shutdown_write(fd: RawFd) -> io::Result<()> {
+    if unsafe { libc::shutdown(fd, libc::SHUT_WR) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+// Sockets opened for UDP ASSOCIATE and BIND listen on the address the client reached us on,
+// so BND.ADDR in the reply is usable; clients on the unix socket are local
+pub(crate) fn listen_ip(local: Option<SocketAddr>) -> IpAddr {
+    match local {
+        Some(addr) => addr.ip(),
+        None => IpAddr::V4(Ipv4Addr::LOCALHOST),
+    }
+}
+
+// A zero linger timeout makes the close that follows send a RST instead of a FIN
+#[cfg(unix)]
+pub(crate) fn set_linger_zero(fd: RawFd) -> io::Result<()> {
+    let linger = libc::linger {
+        l_onoff: 1,
+        l_linger: 0,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_LINGER,
+            &linger as *const libc::linger as *const libc::c_void,
+            std::mem::size_of::<libc::linger>() as libc::socklen_t,
+        )
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn local_ips() -> Vec<IpAddr> {
+    vec![]
+}
@@ -0,0 +1,73 @@
+use futures::FutureExt;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::pin::Pin;
+use std::sync::Once;
+use std::task::{Context, Poll};
+
+thread_local! {
+    static IN_CONNECTION: Cell<bool> = const { Cell::new(false) };
+    static LAST_BACKTRACE: RefCell<Option<Backtrace>> = const { RefCell::new(None) };
+}
+
+static INSTALL_HOOK: Once = Once::new();
+
+// Runs a connection so that a panic inside it only ends that connection, coming back
+// as its message and backtrace. Panics outside any connection still reach the old hook.
+pub(crate) async fn catch_panic<F: Future>(fut: F) -> Result<F::Output, String> {
+    INSTALL_HOOK.call_once(|| {
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if IN_CONNECTION.with(|flag| flag.get()) {
+                let backtrace = Backtrace::force_capture();
+                LAST_BACKTRACE.with(|last| *last.borrow_mut() = Some(backtrace));
+            } else {
+                prev(info);
+            }
+        }));
+    });
+
+    AssertUnwindSafe(InConnection(Box::pin(fut)))
+        .catch_unwind()
+        .await
+        .map_err(|payload| {
+            let backtrace = LAST_BACKTRACE.with(|last| last.borrow_mut().take());
+            match backtrace {
+                Some(backtrace) => format!("{}\n{}", message(&*payload), backtrace),
+                None => message(&*payload),
+            }
+        })
+}
+
+fn message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "Box<dyn Any>".to_string()
+    }
+}
+
+// Flags the thread as running connection code for the duration of each poll
+struct InConnection<F>(Pin<Box<F>>);
+
+impl<F: Future> Future for InConnection<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        struct Reset;
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                IN_CONNECTION.with(|flag| flag.set(false));
+            }
+        }
+
+        IN_CONNECTION.with(|flag| flag.set(true));
+        let _reset = Reset;
+        self.0.as_mut().poll(cx)
+    }
+}
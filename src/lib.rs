@@ -0,0 +1,357 @@
+#[cfg(target_os = "linux")]
+mod acceptor;
+pub mod address;
+pub mod auth;
+mod binding;
+mod breaker;
+pub mod check;
+pub mod client;
+pub mod config;
+mod decoy;
+pub mod diagnose;
+mod domains;
+mod errors;
+#[cfg(target_os = "linux")]
+mod filter;
+mod geoip;
+mod handshake;
+mod http;
+mod ioutil;
+mod isolate;
+mod log;
+mod mirror;
+mod outbound;
+mod policy;
+mod protocol;
+mod queue;
+mod rdns;
+mod reload;
+pub mod report;
+pub mod resolver;
+mod schedule;
+mod server;
+mod shed;
+mod socks4;
+#[cfg(target_os = "linux")]
+pub mod splice;
+mod targets;
+mod tunnels;
+mod udp;
+#[cfg(target_os = "linux")]
+mod unix;
+mod upstream;
+
+pub use address::Address;
+pub use auth::{AuthMethod, AuthResult, AuthStream, Authenticator, NoAuth, StaticUserPass};
+pub use config::Config;
+pub use errors::Socks5Error;
+pub use report::MetricsSink;
+pub use resolver::{Resolver, SystemResolver, TcpFallbackResolver};
+
+use futures::{
+    channel::oneshot,
+    future::{self, BoxFuture, Either, FutureExt},
+};
+use queue::Pause;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// The proxy as something to embed: configure it, `run()` it on any executor,
+// and stop it from elsewhere through a `ShutdownHandle`
+pub struct Socks5Server {
+    config: Config,
+    listener: Option<std::net::TcpListener>,
+    shutdown: ShutdownHandle,
+    stopped: oneshot::Receiver<()>,
+    pause: Arc<Pause>,
+}
+
+// Stops the server it came from; dropping the server's `run()` future does the same
+#[derive(Clone)]
+pub struct ShutdownHandle(Arc<Mutex<Option<oneshot::Sender<()>>>>);
+
+// A server `start()`ed on a thread of its own. Dropping it leaves the server running.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    shutdown: ShutdownHandle,
+    pause: Arc<Pause>,
+    done: BoxFuture<'static, std::io::Result<()>>,
+}
+
+pub struct Builder {
+    config: Config,
+    users: Vec<(String, String)>,
+}
+
+impl Socks5Server {
+    pub fn new(config: Config) -> Self {
+        let (tx, rx) = oneshot::channel();
+        Socks5Server {
+            config,
+            listener: None,
+            shutdown: ShutdownHandle(Arc::new(Mutex::new(Some(tx)))),
+            stopped: rx,
+            pause: Arc::new(Pause::new()),
+        }
+    }
+
+    // Serves on a listener the caller bound and set up itself, for socket options there's
+    // no setting for. `config.bind_addr` and `config.acceptors` go unused.
+    pub fn from_std_listener(listener: std::net::TcpListener, config: Config) -> Self {
+        Socks5Server {
+            listener: Some(listener),
+            ..Socks5Server::new(config)
+        }
+    }
+
+    /// `from_std_listener` for a listening socket handed over as a file descriptor, which the
+    /// server takes ownership of.
+    ///
+    /// # Safety
+    ///
+    /// `fd` has to be an open, listening TCP socket that nothing else will close.
+    #[cfg(unix)]
+    pub unsafe fn from_raw_fd(fd: RawFd, config: Config) -> Self {
+        Socks5Server::from_std_listener(std::net::TcpListener::from_raw_fd(fd), config)
+    }
+
+    pub fn builder() -> Builder {
+        Builder {
+            config: Config::default(),
+            users: vec![],
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Serves until the listener fails or a shutdown is requested. Shutting down stops
+    // accepting, lets open connections finish for up to the drain timeout, then closes the rest.
+    pub async fn run(self) -> std::io::Result<()> {
+        self.serve(None).await
+    }
+
+    // Binds, then serves on a thread of its own, as the server's future isn't `Send`. The
+    // handle has the address actually bound, which is the only way to learn the port when
+    // binding port 0.
+    pub async fn start(self) -> std::io::Result<ServerHandle> {
+        let shutdown = self.shutdown.clone();
+        let pause = self.pause.clone();
+        let (bound_tx, bound_rx) = oneshot::channel();
+        let (done_tx, done_rx) = oneshot::channel();
+        std::thread::Builder::new()
+            .name("socks5-server".into())
+            .spawn(move || {
+                let res = futures::executor::block_on(self.serve(Some(bound_tx)));
+                let _ = done_tx.send(res);
+            })?;
+        let done = async move {
+            done_rx
+                .await
+                .unwrap_or_else(|_| Err(std::io::Error::other("server thread panicked")))
+        }
+        .boxed();
+        match bound_rx.await {
+            Ok(local_addr) => Ok(ServerHandle {
+                local_addr,
+                shutdown,
+                pause,
+                done,
+            }),
+            // Failed before getting as far as binding
+            Err(_) => Err(done
+                .await
+                .err()
+                .unwrap_or_else(|| std::io::Error::other("server stopped before binding"))),
+        }
+    }
+
+    async fn serve(self, bound: Option<oneshot::Sender<SocketAddr>>) -> std::io::Result<()> {
+        let Socks5Server {
+            config,
+            listener,
+            shutdown,
+            stopped,
+            pause,
+        } = self;
+        drop(shutdown);
+        // What --trace dumps is logged at trace level, which it'd be pointless to filter out
+        let level = if config.trace {
+            config::LogLevel::Trace
+        } else {
+            config.log_level
+        };
+        log::init(level, config.log_json);
+
+        let drain = config.drain_timeout;
+        let resume = pause.clone();
+        let stop = async move {
+            // Every handle is gone, so nothing can ask for a shutdown anymore
+            if stopped.await.is_err() {
+                future::pending::<()>().await;
+            }
+            // A paused listener has to get as far as seeing the stop
+            resume.resume();
+        }
+        .boxed()
+        .shared();
+
+        let server = server::start_socks5_server(config, listener, bound, pause, stop.clone());
+        futures::pin_mut!(server);
+        match future::select(server, stop).await {
+            Either::Left((res, _)) => res,
+            Either::Right(((), server)) => match drain {
+                Some(drain) => async_std::future::timeout(drain, server)
+                    .await
+                    .unwrap_or(Ok(())),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // Asks for a shutdown; `join` to wait for the drain to finish
+    pub fn stop(&self) {
+        self.shutdown.shutdown();
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Stops taking new connections, leaving them in the listen backlog, while the ones
+    // already accepted carry on
+    pub fn pause_accepting(&self) {
+        self.pause.pause();
+    }
+
+    pub fn resume_accepting(&self) {
+        self.pause.resume();
+    }
+
+    // Resolves once the server has stopped, with what `run` would have returned
+    pub async fn join(self) -> std::io::Result<()> {
+        self.done.await
+    }
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        if let Some(tx) = self.0.lock().unwrap_or_else(|err| err.into_inner()).take() {
+            let _ = tx.send(());
+        }
+    }
+}
+
+impl Builder {
+    pub fn bind(mut self, addr: impl Into<String>) -> Self {
+        self.config.bind_addr = addr.into();
+        self
+    }
+
+    // Once any user is added, clients have to authenticate with username/password
+    pub fn user(mut self, name: impl Into<String>, password: impl Into<String>) -> Self {
+        self.users.push((name.into(), password.into()));
+        self
+    }
+
+    // Takes over from any users added with `user()`
+    pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.config.authenticator = Arc::new(authenticator);
+        self.users.clear();
+        self
+    }
+
+    // Offers a private auth method under `id`, which has to be in 0x80-0xFE
+    pub fn auth_method(mut self, id: u8, method: impl AuthMethod + 'static) -> Self {
+        assert!(
+            (0x80..=0xfe).contains(&id),
+            "private auth methods are 0x80-0xFE"
+        );
+        self.config.auth_methods.retain(|(other, _)| *other != id);
+        self.config.auth_methods.push((id, Arc::new(method)));
+        self
+    }
+
+    // Ties each user to the address it authenticated from for `period` after its last login
+    pub fn user_binding(mut self, period: Duration) -> Self {
+        self.config.user_binding = Some(period);
+        self
+    }
+
+    pub fn resolver(mut self, resolver: impl Resolver + 'static) -> Self {
+        self.config.resolver = Arc::new(resolver);
+        self
+    }
+
+    pub fn metrics(mut self, sink: impl MetricsSink + 'static) -> Self {
+        self.config.metrics = Some(Arc::new(sink));
+        self
+    }
+
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = max;
+        self
+    }
+
+    pub fn max_handshakes(mut self, max: usize) -> Self {
+        self.config.max_handshakes = Some(max);
+        self
+    }
+
+    pub fn max_tunnels(mut self, max: usize) -> Self {
+        self.config.max_tunnels = Some(max);
+        self
+    }
+
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.config.stall_timeout = Some(timeout);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = Some(timeout);
+        self
+    }
+
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.config.handshake_timeout = Some(timeout);
+        self
+    }
+
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = Some(timeout);
+        self
+    }
+
+    pub fn max_session(mut self, timeout: Duration) -> Self {
+        self.config.max_session = Some(timeout);
+        self
+    }
+
+    pub fn dns_timeout(mut self, timeout: Duration) -> Self {
+        self.config.dns_timeout = Some(timeout);
+        self
+    }
+
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.config.drain_timeout = Some(timeout);
+        self
+    }
+
+    pub fn build(mut self) -> Socks5Server {
+        if !self.users.is_empty() {
+            self.config.authenticator = Arc::new(StaticUserPass::new(self.users));
+        }
+        Socks5Server::new(self.config)
+    }
+}
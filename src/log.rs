@@ -0,0 +1,182 @@
+use crate::config::LogLevel;
+use crate::errors::{ConnContext, ConnError};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::time::Duration;
+use tracing::{field, Span};
+
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static JSON: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn init(level: LogLevel, json: bool) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    JSON.store(json, Ordering::Relaxed);
+}
+
+fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+pub(crate) fn err(msg: impl Display) {
+    emit(LogLevel::Err, msg, &[]);
+}
+
+pub(crate) fn warn(msg: impl Display) {
+    emit(LogLevel::Warn, msg, &[]);
+}
+
+pub(crate) fn info(msg: impl Display) {
+    emit(LogLevel::Info, msg, &[]);
+}
+
+// What `--trace` dumps of connection `id`
+pub(crate) fn trace(id: u64, msg: impl Display) {
+    emit(LogLevel::Trace, msg, &[("id", id.to_string())]);
+}
+
+// Everything a connection logs happens inside its span, so a `tracing` subscriber set
+// by an embedding application sees which client and target each event belongs to
+pub(crate) fn conn_span(ctx: &ConnContext) -> Span {
+    tracing::info_span!(
+        "conn",
+        id = ctx.id,
+        client = %ctx.client,
+        user = field::Empty,
+        target = field::Empty,
+        sent = field::Empty,
+        received = field::Empty,
+        duration_ms = field::Empty,
+        outcome = field::Empty,
+    )
+}
+
+// Once the request is in, who made it and where to
+pub(crate) fn conn_request(ctx: &ConnContext) {
+    let span = Span::current();
+    if let Some(user) = &ctx.user {
+        span.record("user", user.as_str());
+    }
+    if let Some(target) = &ctx.target {
+        span.record("target", target.as_str());
+    }
+}
+
+// A connection that failed; the text form is the error's own Display
+pub(crate) fn conn_error(span: &Span, err: &ConnError, duration: Duration) {
+    span.record("duration_ms", duration.as_millis() as u64);
+    span.record("outcome", err.err.kind());
+    let _entered = span.enter();
+    if !enabled(LogLevel::Err) {
+        return;
+    }
+    let msg = err.err.to_string();
+    let msg = msg.trim_start_matches("[Err] ");
+    if tracing::dispatcher::has_been_set() {
+        tracing::error!(phase = %err.phase, "{}", msg);
+        return;
+    }
+    if !JSON.load(Ordering::Relaxed) {
+        eprintln!("{}", err);
+        return;
+    }
+    let mut fields = conn_fields(&err.ctx);
+    fields.push(("phase", err.phase.to_string()));
+    fields.push(("error", err.err.kind().to_string()));
+    emit(LogLevel::Err, msg, &fields);
+}
+
+// A tunnel that ran to the end
+pub(crate) fn conn_closed(span: &Span, ctx: &ConnContext, duration: Duration) {
+    span.record("sent", ctx.sent);
+    span.record("received", ctx.received);
+    span.record("duration_ms", duration.as_millis() as u64);
+    span.record("outcome", "closed");
+    let _entered = span.enter();
+    if !enabled(LogLevel::Debug) {
+        return;
+    }
+    let mut fields = conn_fields(ctx);
+    fields.push(("sent", ctx.sent.to_string()));
+    fields.push(("received", ctx.received.to_string()));
+    fields.push(("duration_ms", duration.as_millis().to_string()));
+    emit(LogLevel::Debug, "connection closed", &fields);
+}
+
+fn conn_fields(ctx: &ConnContext) -> Vec<(&'static str, String)> {
+    let mut fields = vec![("id", ctx.id.to_string()), ("client", ctx.client.clone())];
+    if let Some(user) = &ctx.user {
+        fields.push(("user", user.clone()));
+    }
+    if let Some(target) = &ctx.target {
+        fields.push(("target", target.clone()));
+    }
+    if !ctx.resolved.is_empty() {
+        let ips = ctx
+            .resolved
+            .iter()
+            .map(|ip| ip.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        fields.push(("resolved", ips));
+    }
+    fields
+}
+
+// With a `tracing` subscriber in place everything goes to it instead, the connection's
+// fields coming from the span it's in
+fn emit(level: LogLevel, msg: impl Display, fields: &[(&str, String)]) {
+    if !enabled(level) {
+        return;
+    }
+    if tracing::dispatcher::has_been_set() {
+        match level {
+            LogLevel::Err => tracing::error!("{}", msg),
+            LogLevel::Warn => tracing::warn!("{}", msg),
+            LogLevel::Info => tracing::info!("{}", msg),
+            LogLevel::Debug => tracing::debug!("{}", msg),
+            LogLevel::Trace => tracing::trace!("{}", msg),
+        }
+        return;
+    }
+    let (name, tag) = match level {
+        LogLevel::Err => ("err", "Err"),
+        LogLevel::Warn => ("warn", "Warn"),
+        LogLevel::Info => ("info", "Info"),
+        LogLevel::Debug => ("debug", "Debug"),
+        LogLevel::Trace => ("trace", "Trace"),
+    };
+
+    if JSON.load(Ordering::Relaxed) {
+        let mut line = format!(
+            "{{\"level\":\"{}\",\"msg\":\"{}\"",
+            name,
+            escape(&msg.to_string())
+        );
+        for (key, value) in fields {
+            line += &format!(",\"{}\":\"{}\"", key, escape(value));
+        }
+        eprintln!("{}}}", line);
+    } else if fields.is_empty() {
+        eprintln!("[{}] {}", tag, msg);
+    } else {
+        let fields = fields
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join(" ");
+        eprintln!("[{}] {} ({})", tag, msg, fields);
+    }
+}
+
+pub(crate) fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -1,11 +1,59 @@
-mod errors;
-mod ioutil;
-mod server;
+#[cfg(target_os = "linux")]
+use async_socks5::splice;
+use async_socks5::{check, diagnose, report, Config, Socks5Server};
 
 fn main() {
-    let args = std::env::args().collect::<Vec<_>>();
-    let default_addr = &"0.0.0.0:1080".to_string();
-    let bind_addr = args.get(1).unwrap_or(default_addr);
+    let mut args = std::env::args().skip(1).peekable();
+    if args.peek().map(String::as_str) == Some("check-config") {
+        args.next();
+        std::process::exit(check::run(args));
+    }
 
-    futures::executor::block_on(server::start_socks5_server(bind_addr, 0)).unwrap();
+    let config = match Config::from_args(args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+
+    if config.print_exit_policy {
+        match &config.exit_policy {
+            Some(policy) => print!("{}", policy),
+            None => println!("accept *:*"),
+        }
+        return;
+    }
+
+    if config.diagnose {
+        std::process::exit(diagnose::run(&config));
+    }
+
+    if config.print_capabilities {
+        print_capabilities();
+        return;
+    }
+
+    let report_path = config.report.clone();
+    let server = Socks5Server::new(config);
+    let shutdown = server.shutdown_handle();
+    report::install(report_path.clone(), move || shutdown.shutdown());
+    let res = futures::executor::block_on(server.run());
+    report::finish(report_path.as_deref());
+    res.unwrap();
+}
+
+fn print_capabilities() {
+    println!("copy: supported");
+    #[cfg(target_os = "linux")]
+    println!(
+        "splice: {}",
+        if splice::supported() {
+            "supported"
+        } else {
+            "unsupported"
+        }
+    );
+    #[cfg(not(target_os = "linux"))]
+    println!("splice: unsupported");
 }
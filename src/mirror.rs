@@ -0,0 +1,62 @@
+use crate::{config::Config, errors::ConnContext};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Mutex;
+
+// Opened on first use and shared, so mirroring costs one datagram per connection
+static SOCKET: Mutex<Option<UdpSocket>> = Mutex::new(None);
+
+// Copies what a CONNECT asked for (never its payload) to the `--mirror` endpoint as one
+// datagram of `key=value` pairs. Fire and forget: a slow or missing endpoint can't hold up the client.
+pub(crate) fn connect(ctx: &ConnContext, target: &[SocketAddr], config: &Config) {
+    let endpoint = match config.mirror {
+        Some(endpoint) => endpoint,
+        None => return,
+    };
+    if let Some(policy) = &config.mirror_policy {
+        if !target.iter().any(|addr| policy.allows(addr)) {
+            return;
+        }
+    }
+
+    let mut line = format!("id={} client={}", ctx.id, value(&ctx.client));
+    if let Some(user) = &ctx.user {
+        line.push_str(&format!(" user={}", value(user)));
+    }
+    if let Some(target) = &ctx.target {
+        line.push_str(&format!(" target={}", value(target)));
+    }
+    let resolved = target
+        .iter()
+        .map(|addr| addr.to_string())
+        .collect::<Vec<_>>()
+        .join(",");
+    line.push_str(&format!(" resolved={}\n", resolved));
+
+    let mut socket = SOCKET.lock().unwrap_or_else(|err| err.into_inner());
+    if socket.is_none() {
+        let bind: SocketAddr = if endpoint.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        *socket = UdpSocket::bind(bind)
+            .and_then(|s| s.set_nonblocking(true).map(|_| s))
+            .ok();
+    }
+    if let Some(socket) = socket.as_ref() {
+        let _ = socket.send_to(line.as_bytes(), endpoint);
+    }
+}
+
+// Whitespace and control characters become `_`, so a value can't break up the pairs or the line
+fn value(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_whitespace() || c.is_control() {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
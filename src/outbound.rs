@@ -0,0 +1,408 @@
+use crate::config::{AddressFamily, Config, OutboundBind};
+use async_io::Async;
+use async_std::io;
+use async_std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use futures::{
+    future::{self, Either},
+    stream::{FuturesUnordered, StreamExt},
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+// Linux >= 6.3, not exported by libc yet
+#[cfg(target_os = "linux")]
+const IP_LOCAL_PORT_RANGE: libc::c_int = 51;
+// Linux >= 5.0
+#[cfg(target_os = "linux")]
+const SO_BINDTOIFINDEX: libc::c_int = 62;
+// Linux >= 4.2
+#[cfg(target_os = "linux")]
+const IP_BIND_ADDRESS_NO_PORT: libc::c_int = 24;
+
+// RFC 8305's recommended Connection Attempt Delay
+pub(crate) const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+static NEXT_PORT: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) async fn connect(target: &[SocketAddr], config: &Config) -> io::Result<TcpStream> {
+    let mut last_err = None;
+
+    match config.happy_eyeballs {
+        Some(delay) if target.len() > 1 => {
+            let addrs = interleave(target, config.prefer_family);
+            return race(addrs, delay, config).await;
+        }
+        _ => (),
+    }
+
+    for addr in target {
+        match attempt(*addr, config).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(no_addresses))
+}
+
+// Starts on the next address whenever the last one started fails or hasn't connected
+// within `delay`, keeping the earlier attempts going; the first to connect wins
+async fn race(addrs: Vec<SocketAddr>, delay: Duration, config: &Config) -> io::Result<TcpStream> {
+    let mut addrs = addrs.into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = None;
+
+    attempts.extend(addrs.next().map(|addr| attempt(addr, config)));
+    while !attempts.is_empty() {
+        let timer = match addrs.len() {
+            0 => Either::Left(future::pending()),
+            _ => Either::Right(async_std::task::sleep(delay)),
+        };
+        futures::pin_mut!(timer);
+        match future::select(attempts.next(), timer).await {
+            Either::Left((Some(Ok(stream)), _)) => return Ok(stream),
+            Either::Left((Some(Err(err)), _)) => last_err = Some(err),
+            Either::Left((None, _)) | Either::Right(_) => (),
+        }
+        attempts.extend(addrs.next().map(|addr| attempt(addr, config)));
+    }
+
+    Err(last_err.unwrap_or_else(no_addresses))
+}
+
+// Alternates between families, the preferred one first, keeping the resolver's order
+// within each (RFC 8305 section 4)
+fn interleave(addrs: &[SocketAddr], prefer: AddressFamily) -> Vec<SocketAddr> {
+    let prefer_v6 = prefer == AddressFamily::Ipv6;
+    let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.iter().partition(|addr| addr.is_ipv6() == prefer_v6);
+    let (mut first, mut second) = (first.into_iter(), second.into_iter());
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    while ordered.len() < addrs.len() {
+        ordered.extend(first.next());
+        ordered.extend(second.next());
+    }
+    ordered
+}
+
+// A single address, as far as the circuit breaker is concerned
+async fn attempt(addr: SocketAddr, config: &Config) -> io::Result<TcpStream> {
+    crate::breaker::check(addr, config)?;
+    let connect = connect_addr(addr, config);
+    let res = match config.connect_timeout {
+        Some(connect_timeout) => io::timeout(connect_timeout, connect).await,
+        None => connect.await,
+    };
+    crate::breaker::record(addr, res.is_ok(), config);
+    res
+}
+
+fn no_addresses() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "could not resolve to any addresses",
+    )
+}
+
+async fn connect_addr(mut addr: SocketAddr, config: &Config) -> io::Result<TcpStream> {
+    let bind = config
+        .exit_policy
+        .as_ref()
+        .and_then(|policy| policy.bind_for(&addr))
+        .or(config.outbound_bind);
+
+    if let (SocketAddr::V4(v4), Some((prefix, len))) = (addr, config.nat64_prefix) {
+        addr = SocketAddr::new(
+            IpAddr::V6(synthesize_nat64(prefix, len, *v4.ip())),
+            v4.port(),
+        );
+    }
+
+    // A link-local target is meaningless without a zone, and SOCKS has no way to carry one
+    if let (SocketAddr::V6(v6), Some(scope)) = (&mut addr, config.link_local_scope) {
+        if is_unicast_link_local(v6.ip()) && v6.scope_id() == 0 {
+            v6.set_scope_id(scope);
+        }
+    }
+
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+
+    if let Some(ttl) = config.outbound_ttl {
+        match addr {
+            SocketAddr::V4(_) => socket.set_ttl(ttl)?,
+            SocketAddr::V6(_) => socket.set_unicast_hops_v6(ttl)?,
+        };
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        if let (SocketAddr::V6(_), Some(pref)) = (addr, config.ipv6_source) {
+            set_ipv6_source_preference(&socket, pref)?;
+        }
+    }
+
+    let source = match bind {
+        Some(OutboundBind::Addr(ip)) if ip.is_ipv4() != addr.is_ipv4() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("outbound bind address {} can't reach {}", ip, addr),
+            ));
+        }
+        Some(OutboundBind::Addr(ip)) => Some(ip),
+        #[cfg(target_os = "linux")]
+        Some(OutboundBind::Interface(index)) => {
+            bind_interface(&socket, index)?;
+            None
+        }
+        None => None,
+    };
+
+    match (config.outbound_port_range, source) {
+        (Some(range), _) => bind_port_range(&socket, &addr, range, source)?,
+        (None, Some(ip)) => bind_source(&socket, ip)?,
+        (None, None) => (),
+    }
+
+    socket.set_nonblocking(true)?;
+    match socket.connect(&addr.into()) {
+        Ok(()) => (),
+        #[cfg(unix)]
+        Err(err) if err.raw_os_error() == Some(libc::EINPROGRESS) => (),
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => (),
+        Err(err) => return Err(err),
+    };
+
+    let stream = Async::new(socket.into_tcp_stream())?;
+    stream.writable().await?;
+    if let Some(err) = stream.get_ref().take_error()? {
+        return Err(err);
+    }
+
+    Ok(TcpStream::from(stream.into_inner()?))
+}
+
+// Embeds an IPv4 address into a NAT64 prefix as laid out in RFC 6052 section 2.2
+fn synthesize_nat64(prefix: Ipv6Addr, len: u8, v4: Ipv4Addr) -> Ipv6Addr {
+    let mut octets = prefix.octets();
+    let mut pos = (len / 8) as usize;
+    for b in octets[pos..].iter_mut() {
+        *b = 0;
+    }
+
+    for b in v4.octets().iter() {
+        // Bits 64..71 are reserved and must stay zero
+        if pos == 8 {
+            pos += 1;
+        }
+        octets[pos] = *b;
+        pos += 1;
+    }
+
+    Ipv6Addr::from(octets)
+}
+
+// The IPv4 address `synthesize_nat64` embedded, if `ip` is under the prefix
+pub(crate) fn extract_nat64(ip: &Ipv6Addr, prefix: Ipv6Addr, len: u8) -> Option<Ipv4Addr> {
+    let octets = ip.octets();
+    let mut pos = (len / 8) as usize;
+    if octets[..pos] != prefix.octets()[..pos] {
+        return None;
+    }
+
+    let mut v4 = [0u8; 4];
+    for b in v4.iter_mut() {
+        if pos == 8 {
+            pos += 1;
+        }
+        *b = octets[pos];
+        pos += 1;
+    }
+    Some(Ipv4Addr::from(v4))
+}
+
+fn is_unicast_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+fn bind_port_range(
+    socket: &Socket,
+    addr: &SocketAddr,
+    (lo, hi): (u16, u16),
+    source: Option<IpAddr>,
+) -> io::Result<()> {
+    // Let the kernel pick from the range at connect time if it knows how to,
+    // which keeps the 4-tuple reuse that an explicit bind would give up
+    #[cfg(target_os = "linux")]
+    {
+        if set_ip_local_port_range(socket, lo, hi).is_ok() {
+            return match source {
+                Some(ip) => bind_source(socket, ip),
+                None => Ok(()),
+            };
+        }
+    }
+
+    let ip = match (source, addr) {
+        (Some(ip), _) => ip,
+        (None, SocketAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (None, SocketAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let span = (hi - lo) as usize + 1;
+    let start = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+    for i in 0..span {
+        let port = lo + ((start + i) % span) as u16;
+        match socket.bind(&SocketAddr::new(ip, port).into()) {
+            Ok(()) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(err) => return Err(err),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        "no free port left in the outbound port range",
+    ))
+}
+
+// Fixes the source address only, the port is still picked at connect time where the kernel
+// can defer it
+fn bind_source(socket: &Socket, ip: IpAddr) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        let on: libc::c_int = 1;
+        unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_IP,
+                IP_BIND_ADDRESS_NO_PORT,
+                &on as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+    }
+
+    socket.bind(&SocketAddr::new(ip, 0).into())
+}
+
+#[cfg(target_os = "linux")]
+fn bind_interface(socket: &Socket, index: u32) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let index = index as libc::c_int;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            SO_BINDTOIFINDEX,
+            &index as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_ip_local_port_range(socket: &Socket, lo: u16, hi: u16) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let range = lo as u32 | (hi as u32) << 16;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IP,
+            IP_LOCAL_PORT_RANGE,
+            &range as *const u32 as *const libc::c_void,
+            std::mem::size_of::<u32>() as libc::socklen_t,
+        )
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_ipv6_source_preference(
+    socket: &Socket,
+    pref: crate::config::SourcePreference,
+) -> io::Result<()> {
+    use crate::config::SourcePreference;
+    use std::os::unix::io::AsRawFd;
+
+    let flags = match pref {
+        SourcePreference::Temporary => libc::IPV6_PREFER_SRC_TMP,
+        SourcePreference::Public => libc::IPV6_PREFER_SRC_PUBLIC,
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_ADDR_PREFERENCES,
+            &flags as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nat64_round_trip() {
+        // RFC 6052 section 2.4, 192.0.2.33 under each prefix length
+        let v4 = Ipv4Addr::new(192, 0, 2, 33);
+        for (prefix, len, synthesized) in [
+            ("2001:db8::", 32, "2001:db8:c000:221::"),
+            ("2001:db8:100::", 40, "2001:db8:1c0:2:21::"),
+            ("2001:db8:122::", 48, "2001:db8:122:c000:2:2100::"),
+            ("2001:db8:122:300::", 56, "2001:db8:122:3c0:0:221::"),
+            ("2001:db8:122:344::", 64, "2001:db8:122:344:c0:2:2100:0"),
+            ("2001:db8:122:344::", 96, "2001:db8:122:344::c000:221"),
+            ("64:ff9b::", 96, "64:ff9b::c000:221"),
+        ] {
+            let prefix = prefix.parse().unwrap();
+            let ip = synthesize_nat64(prefix, len, v4);
+            assert_eq!(ip, synthesized.parse::<Ipv6Addr>().unwrap(), "/{}", len);
+            assert_eq!(extract_nat64(&ip, prefix, len), Some(v4), "/{}", len);
+        }
+    }
+
+    #[test]
+    fn nat64_outside_the_prefix() {
+        let prefix = "64:ff9b::".parse().unwrap();
+        assert_eq!(
+            extract_nat64(&"2001:db8::c000:221".parse().unwrap(), prefix, 96),
+            None
+        );
+        // Whatever was in the prefix past its length is cleared
+        let ip = synthesize_nat64(
+            "2001:db8::ffff".parse().unwrap(),
+            32,
+            Ipv4Addr::new(10, 0, 0, 1),
+        );
+        assert_eq!(ip, "2001:db8:a00:1::".parse::<Ipv6Addr>().unwrap());
+    }
+}
@@ -0,0 +1,398 @@
+use crate::{
+    config::{ErrorMode, OutboundBind},
+    errors::Socks5Error,
+};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Clone, Copy)]
+enum Net {
+    Any,
+    Any4,
+    Any6,
+    Cidr(IpAddr, u8),
+}
+
+// What a reject rule does instead of `--on-error`
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum DenyAction {
+    Respond(ErrorMode),
+    // Connects the client to a notice service instead, as if it were the target
+    Redirect(SocketAddr),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Rule {
+    accept: bool,
+    net: Net,
+    ports: (u16, u16),
+    deny: Option<DenyAction>,
+    // Overrides `--outbound-bind` for what an accept rule lets through
+    bind: Option<OutboundBind>,
+}
+
+// A Tor-style exit policy: `accept|reject ADDR[/BITS]:PORT[-PORT]` rules separated by
+// commas or newlines, where ADDR may be `*`, `*4` or `*6` and PORT may be `*`. A reject
+// rule may end in `reply`, `close`, `reset` or `redirect=ADDR:PORT` to override how the
+// client is turned away, and an accept rule may end in `bind=ADDR|IFACE` to send what it
+// lets through from there instead of `--outbound-bind`. The first
+// matching rule decides; a destination no rule matches gets the default, reject unless
+// `--exit-policy-default accept` says otherwise.
+#[derive(Debug)]
+pub struct ExitPolicy {
+    // Swapped whole when --exit-policy-file changes, so a decision sees one version or the other
+    rules: RwLock<Arc<Vec<Rule>>>,
+    default_accept: bool,
+}
+
+impl ExitPolicy {
+    pub(crate) fn parse(s: &str) -> Result<ExitPolicy, Socks5Error> {
+        let rules = s
+            .split([',', '\n'])
+            .map(str::trim)
+            .filter(|r| !r.is_empty() && !r.starts_with('#'))
+            .map(parse_rule)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(ExitPolicy {
+            rules: RwLock::new(Arc::new(rules)),
+            default_accept: false,
+        })
+    }
+
+    // Takes on the rules of `other`, keeping the default
+    pub(crate) fn replace(&self, other: ExitPolicy) {
+        *self.rules.write().unwrap_or_else(|err| err.into_inner()) = other.rules();
+    }
+
+    fn rules(&self) -> Arc<Vec<Rule>> {
+        self.rules
+            .read()
+            .unwrap_or_else(|err| err.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn set_default(&mut self, accept: bool) {
+        self.default_accept = accept;
+    }
+
+    pub(crate) fn allows(&self, addr: &SocketAddr) -> bool {
+        match self.matching_rule(addr) {
+            Some(rule) => rule.accept,
+            None => self.default_accept,
+        }
+    }
+
+    // The override of the reject rule `addr` falls under, if it has one
+    pub(crate) fn deny_action(&self, addr: &SocketAddr) -> Option<DenyAction> {
+        self.matching_rule(addr)
+            .filter(|rule| !rule.accept)
+            .and_then(|rule| rule.deny)
+    }
+
+    // Where to originate a connection to `addr` from, if its accept rule says
+    pub(crate) fn bind_for(&self, addr: &SocketAddr) -> Option<OutboundBind> {
+        self.matching_rule(addr)
+            .filter(|rule| rule.accept)
+            .and_then(|rule| rule.bind)
+    }
+
+    pub(crate) fn matching_rule(&self, addr: &SocketAddr) -> Option<Rule> {
+        self.rules().iter().find(|rule| rule.matches(addr)).copied()
+    }
+
+    // Whether `port` is accepted, and the reject rule's override if not, when that holds
+    // for any address; None when it depends on what the target resolves to
+    pub(crate) fn decide_port(&self, port: u16) -> Option<(bool, Option<DenyAction>)> {
+        for rule in self.rules().iter() {
+            let (lo, hi) = rule.ports;
+            if port < lo || port > hi {
+                continue;
+            }
+            return match rule.net {
+                Net::Any => Some((rule.accept, rule.deny)),
+                _ => None,
+            };
+        }
+        Some((self.default_accept, None))
+    }
+}
+
+impl std::fmt::Display for ExitPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for rule in self.rules().iter() {
+            writeln!(f, "{}", rule)?;
+        }
+        if self.default_accept {
+            writeln!(f, "accept *:*")?;
+        }
+        Ok(())
+    }
+}
+
+impl Rule {
+    fn matches(&self, addr: &SocketAddr) -> bool {
+        let (lo, hi) = self.ports;
+        if addr.port() < lo || addr.port() > hi {
+            return false;
+        }
+
+        match (self.net, addr.ip()) {
+            (Net::Any, _) => true,
+            (Net::Any4, ip) => ip.is_ipv4(),
+            (Net::Any6, ip) => ip.is_ipv6(),
+            (Net::Cidr(net, bits), ip) => cidr_contains((net, bits), ip),
+        }
+    }
+}
+
+// `ADDR[/BITS]`, with IPv6 addresses optionally in brackets
+pub(crate) fn parse_cidr(s: &str) -> Option<(IpAddr, u8)> {
+    let mut parts = s.splitn(2, '/');
+    let ip = parts
+        .next()
+        .map(|ip| ip.trim_start_matches('[').trim_end_matches(']'))
+        .and_then(|ip| ip.parse::<IpAddr>().ok())?;
+    let max_bits = if ip.is_ipv4() { 32 } else { 128 };
+    let bits = match parts.next() {
+        Some(bits) => bits.parse::<u8>().ok()?,
+        None => max_bits,
+    };
+    if bits > max_bits {
+        return None;
+    }
+    Some((ip, bits))
+}
+
+pub(crate) fn cidr_contains((net, bits): (IpAddr, u8), ip: IpAddr) -> bool {
+    match (net, ip) {
+        (IpAddr::V4(net), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - bits as u32).unwrap_or(0);
+            u32::from(net) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(net), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - bits as u32).unwrap_or(0);
+            u128::from(net) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ", if self.accept { "accept" } else { "reject" })?;
+        match self.net {
+            Net::Any => write!(f, "*")?,
+            Net::Any4 => write!(f, "*4")?,
+            Net::Any6 => write!(f, "*6")?,
+            Net::Cidr(IpAddr::V4(ip), 32) => write!(f, "{}", ip)?,
+            Net::Cidr(IpAddr::V6(ip), 128) => write!(f, "[{}]", ip)?,
+            Net::Cidr(IpAddr::V4(ip), bits) => write!(f, "{}/{}", ip, bits)?,
+            Net::Cidr(IpAddr::V6(ip), bits) => write!(f, "[{}]/{}", ip, bits)?,
+        }
+        match self.ports {
+            (1, u16::MAX) => write!(f, ":*")?,
+            (lo, hi) if lo == hi => write!(f, ":{}", lo)?,
+            (lo, hi) => write!(f, ":{}-{}", lo, hi)?,
+        }
+        match self.deny {
+            Some(DenyAction::Respond(ErrorMode::Reply)) => write!(f, " reply"),
+            Some(DenyAction::Respond(ErrorMode::Close)) => write!(f, " close"),
+            #[cfg(unix)]
+            Some(DenyAction::Respond(ErrorMode::Reset)) => write!(f, " reset"),
+            Some(DenyAction::Redirect(addr)) => write!(f, " redirect={}", addr),
+            None => match self.bind {
+                Some(bind) => write!(f, " bind={}", bind),
+                None => Ok(()),
+            },
+        }
+    }
+}
+
+fn parse_rule(s: &str) -> Result<Rule, Socks5Error> {
+    let invalid = || Socks5Error::InvalidConfig(format!("invalid exit policy rule {}", s));
+
+    let mut parts = s.split_whitespace();
+    let accept = match parts.next() {
+        Some("accept") => true,
+        Some("reject") => false,
+        _ => return Err(invalid()),
+    };
+    let pattern = parts.next().ok_or_else(invalid)?;
+    let option = parts.next();
+    let bind = match option {
+        Some(option) if accept => match option.strip_prefix("bind=") {
+            Some(bind) => Some(crate::config::parse_outbound_bind(bind).map_err(|_| invalid())?),
+            None => return Err(invalid()),
+        },
+        _ => None,
+    };
+    let deny = match option {
+        None => None,
+        Some(_) if accept => None,
+        Some("reply") => Some(DenyAction::Respond(ErrorMode::Reply)),
+        Some("close") => Some(DenyAction::Respond(ErrorMode::Close)),
+        #[cfg(unix)]
+        Some("reset") => Some(DenyAction::Respond(ErrorMode::Reset)),
+        Some(action) => match action.strip_prefix("redirect=") {
+            Some(addr) => Some(DenyAction::Redirect(addr.parse().map_err(|_| invalid())?)),
+            None => return Err(invalid()),
+        },
+    };
+    if parts.next().is_some() {
+        return Err(invalid());
+    }
+
+    let colon = pattern.rfind(':').ok_or_else(invalid)?;
+    let (addr, ports) = (&pattern[..colon], &pattern[colon + 1..]);
+
+    let net = match addr {
+        "*" => Net::Any,
+        "*4" => Net::Any4,
+        "*6" => Net::Any6,
+        _ => {
+            let (ip, bits) = parse_cidr(addr).ok_or_else(invalid)?;
+            Net::Cidr(ip, bits)
+        }
+    };
+
+    let ports = if ports == "*" {
+        (1, u16::MAX)
+    } else {
+        let mut parts = ports.splitn(2, '-');
+        let lo = parts
+            .next()
+            .and_then(|p| p.parse::<u16>().ok())
+            .ok_or_else(invalid)?;
+        let hi = match parts.next() {
+            Some(hi) => hi.parse::<u16>().map_err(|_| invalid())?,
+            None => lo,
+        };
+        if lo == 0 || lo > hi {
+            return Err(invalid());
+        }
+        (lo, hi)
+    };
+
+    Ok(Rule {
+        accept,
+        net,
+        ports,
+        deny,
+        bind,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let policy = ExitPolicy::parse(
+            "reject 10.0.0.0/8:*, accept *:80-443\n# comment\naccept *6:22\nreject [::1]:*",
+        )
+        .unwrap();
+        assert!(!policy.allows(&addr("10.1.2.3:80")));
+        assert!(policy.allows(&addr("192.0.2.1:80")));
+        assert!(policy.allows(&addr("192.0.2.1:443")));
+        assert!(!policy.allows(&addr("192.0.2.1:444")));
+        assert!(policy.allows(&addr("[2001:db8::1]:22")));
+        assert!(!policy.allows(&addr("192.0.2.1:22")));
+        // Nothing matches, so the default decides
+        assert!(!policy.allows(&addr("[::1]:8080")));
+
+        let mut policy = ExitPolicy::parse("reject *4:25").unwrap();
+        policy.set_default(true);
+        assert!(!policy.allows(&addr("192.0.2.1:25")));
+        assert!(policy.allows(&addr("[2001:db8::1]:25")));
+        assert!(policy.allows(&addr("192.0.2.1:26")));
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        for rule in [
+            "allow *:*",
+            "accept *",
+            "accept 10.0.0.0/33:*",
+            "accept *:0",
+            "accept *:90-80",
+            "accept *:65536",
+            "accept *:* reply",
+            "reject *:* bind=192.0.2.1",
+            "reject *:* redirect=nowhere",
+            "reject *:* bounce",
+            "reject *:* close reply",
+        ] {
+            assert!(ExitPolicy::parse(rule).is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn displays_what_it_parsed() {
+        let rules = "accept 192.0.2.0/24:80 bind=192.0.2.9\nreject 198.51.100.7:*\nreject [2001:db8::]/32:25-587 close\nreject *:23 redirect=127.0.0.1:8023\naccept *4:443\nreject *6:* reply\n";
+        let policy = ExitPolicy::parse(rules).unwrap();
+        assert_eq!(policy.to_string(), rules);
+
+        let mut policy = ExitPolicy::parse("reject *:25").unwrap();
+        policy.set_default(true);
+        assert_eq!(policy.to_string(), "reject *:25\naccept *:*\n");
+    }
+
+    #[test]
+    fn rule_actions() {
+        let policy = ExitPolicy::parse(
+            "reject *:23 redirect=127.0.0.1:8023, reject *:25 close, reject *:26 reply, reject *:27, accept *:80 bind=192.0.2.9, accept *:81",
+        )
+        .unwrap();
+        assert!(matches!(
+            policy.deny_action(&addr("192.0.2.1:23")),
+            Some(DenyAction::Redirect(to)) if to == addr("127.0.0.1:8023")
+        ));
+        assert!(matches!(
+            policy.deny_action(&addr("192.0.2.1:25")),
+            Some(DenyAction::Respond(ErrorMode::Close))
+        ));
+        assert!(matches!(
+            policy.deny_action(&addr("192.0.2.1:26")),
+            Some(DenyAction::Respond(ErrorMode::Reply))
+        ));
+        assert!(policy.deny_action(&addr("192.0.2.1:27")).is_none());
+        assert!(policy.deny_action(&addr("192.0.2.1:80")).is_none());
+
+        assert_eq!(
+            policy.bind_for(&addr("192.0.2.1:80")),
+            Some(OutboundBind::Addr("192.0.2.9".parse().unwrap()))
+        );
+        assert_eq!(policy.bind_for(&addr("192.0.2.1:81")), None);
+        assert_eq!(policy.bind_for(&addr("192.0.2.1:23")), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reset_action() {
+        let policy = ExitPolicy::parse("reject *:* reset").unwrap();
+        assert!(matches!(
+            policy.deny_action(&addr("192.0.2.1:80")),
+            Some(DenyAction::Respond(ErrorMode::Reset))
+        ));
+        assert_eq!(policy.to_string(), "reject *:* reset\n");
+    }
+
+    #[test]
+    fn decides_ports_before_resolving() {
+        let policy =
+            ExitPolicy::parse("reject *:25 close, reject 10.0.0.0/8:80, accept *:80").unwrap();
+        assert!(matches!(
+            policy.decide_port(25),
+            Some((false, Some(DenyAction::Respond(ErrorMode::Close))))
+        ));
+        // Depends on the address a name resolves to
+        assert!(policy.decide_port(80).is_none());
+        assert!(matches!(policy.decide_port(443), Some((false, None))));
+    }
+}
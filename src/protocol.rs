@@ -0,0 +1,364 @@
+use crate::{address::Address, errors::Socks5Error};
+use std::{
+    convert::TryInto,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+};
+
+// The SOCKS5 wire format: RFC 1928, RFC 1929 and Tor's RESOLVE extensions. Everything is
+// built and taken apart byte by byte, so neither byte order nor alignment can go wrong.
+// Nothing here does IO; see `handshake` for the state machines that string messages together.
+
+pub(crate) const SOCKS_VERSION: u8 = 0x5;
+pub(crate) const NO_AUTH: u8 = 0x0;
+pub(crate) const USER_PASS: u8 = 0x2;
+pub(crate) const NO_ACCEPTABLE: u8 = 0xff;
+pub(crate) const USER_PASS_VERSION: u8 = 0x1;
+pub(crate) const AUTH_SUCCESS: u8 = 0x0;
+pub(crate) const AUTH_FAILURE: u8 = 0x1;
+pub(crate) const RSV: u8 = 0x0;
+pub(crate) const CMD_CONNECT: u8 = 0x1;
+pub(crate) const CMD_BIND: u8 = 0x2;
+pub(crate) const CMD_UDP_ASSOCIATE: u8 = 0x3;
+// Tor's extensions for doing DNS through the proxy
+pub(crate) const CMD_RESOLVE: u8 = 0xf0;
+pub(crate) const CMD_RESOLVE_PTR: u8 = 0xf1;
+pub(crate) const TYP_IPV4: u8 = 0x1;
+pub(crate) const TYP_DOMAIN: u8 = 0x3;
+pub(crate) const TYP_IPV6: u8 = 0x4;
+pub(crate) const RESP_SUCCESS: u8 = 0x0;
+pub(crate) const RESP_GENERAL_FAILURE: u8 = 0x1;
+pub(crate) const RESP_NOT_ALLOWED: u8 = 0x2;
+pub(crate) const RESP_NETWORK_UNREACHABLE: u8 = 0x3;
+pub(crate) const RESP_HOST_UNREACHABLE: u8 = 0x4;
+pub(crate) const RESP_CONNECTION_REFUSED: u8 = 0x5;
+pub(crate) const RESP_TTL_EXPIRED: u8 = 0x6;
+pub(crate) const RESP_CMD_NOT_SUPPORTED: u8 = 0x7;
+pub(crate) const RESP_ADDR_NOT_SUPPORTED: u8 = 0x8;
+
+// VER CMD RSV, then the destination
+#[derive(Debug)]
+pub(crate) struct Request {
+    pub cmd: u8,
+    pub target: Address,
+}
+
+// VER REP RSV, then BND; 0.0.0.0:0 goes out when there's no address to tell
+#[derive(Debug)]
+pub(crate) struct Reply {
+    pub rep: u8,
+    pub bnd: Option<Address>,
+}
+
+// How much of a message the front of a buffer holds
+#[derive(Debug)]
+pub(crate) enum Decoded<T> {
+    // At least this many more bytes are needed before anything can be said
+    Incomplete(usize),
+    // The message, and how many bytes of the buffer it took up
+    Complete(T, usize),
+}
+
+// RFC 1929 sub-negotiation: VER ULEN UNAME PLEN PASSWD
+pub(crate) struct UserPass {
+    pub username: String,
+    pub password: Vec<u8>,
+}
+
+impl Request {
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, Socks5Error> {
+        let mut buf = vec![SOCKS_VERSION, self.cmd, RSV];
+        encode_address(&self.target, &mut buf)?;
+        Ok(buf)
+    }
+
+    // An unsupported command is turned away before its address is looked at
+    pub(crate) fn decode(buf: &[u8]) -> Result<Decoded<Request>, Socks5Error> {
+        if buf.len() < 4 {
+            return Ok(Decoded::Incomplete(4 - buf.len()));
+        }
+        if buf[0] != SOCKS_VERSION {
+            return Err(Socks5Error::UnsupportedVersion);
+        }
+        let cmd = buf[1];
+        if !matches!(
+            cmd,
+            CMD_CONNECT | CMD_BIND | CMD_UDP_ASSOCIATE | CMD_RESOLVE | CMD_RESOLVE_PTR
+        ) {
+            return Err(Socks5Error::UnsupportedCommand);
+        }
+
+        Ok(match decode_addr(&buf[3..])? {
+            Decoded::Incomplete(more) => Decoded::Incomplete(more),
+            Decoded::Complete(target, len) => Decoded::Complete(Request { cmd, target }, 3 + len),
+        })
+    }
+}
+
+impl Reply {
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, Socks5Error> {
+        let unspecified = Address::from_ip(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+        let mut buf = vec![SOCKS_VERSION, self.rep, RSV];
+        encode_address(self.bnd.as_ref().unwrap_or(&unspecified), &mut buf)?;
+        Ok(buf)
+    }
+
+    // A failure's BND means nothing and some servers cut it short, so it isn't waited for
+    pub(crate) fn decode(buf: &[u8]) -> Result<Decoded<Reply>, Socks5Error> {
+        if buf.len() < 4 {
+            return Ok(Decoded::Incomplete(4 - buf.len()));
+        }
+        if buf[0] != SOCKS_VERSION {
+            return Err(Socks5Error::UnsupportedVersion);
+        }
+        let rep = buf[1];
+        if rep != RESP_SUCCESS {
+            return Ok(Decoded::Complete(Reply { rep, bnd: None }, 4));
+        }
+
+        Ok(match decode_addr(&buf[3..])? {
+            Decoded::Incomplete(more) => Decoded::Incomplete(more),
+            Decoded::Complete(bnd, len) => Decoded::Complete(
+                Reply {
+                    rep,
+                    bnd: Some(bnd),
+                },
+                3 + len,
+            ),
+        })
+    }
+}
+
+impl UserPass {
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, Socks5Error> {
+        // A single length byte for each
+        if self.username.len() > 0xff || self.password.len() > 0xff {
+            return Err(Socks5Error::AuthFailed(self.username.clone()));
+        }
+        let mut buf = vec![USER_PASS_VERSION, self.username.len() as u8];
+        buf.extend_from_slice(self.username.as_bytes());
+        buf.push(self.password.len() as u8);
+        buf.extend_from_slice(&self.password);
+        Ok(buf)
+    }
+
+    pub(crate) fn decode(buf: &[u8]) -> Result<Decoded<UserPass>, Socks5Error> {
+        if buf.len() < 2 {
+            return Ok(Decoded::Incomplete(2 - buf.len()));
+        }
+        if buf[0] != USER_PASS_VERSION {
+            return Err(Socks5Error::UnsupportedVersion);
+        }
+
+        let ulen = buf[1] as usize;
+        let plen = match buf.get(2 + ulen) {
+            Some(&plen) => plen as usize,
+            None => return Ok(Decoded::Incomplete(2 + ulen + 1 - buf.len())),
+        };
+        let len = 2 + ulen + 1 + plen;
+        if buf.len() < len {
+            return Ok(Decoded::Incomplete(len - buf.len()));
+        }
+
+        let username = String::from_utf8_lossy(&buf[2..2 + ulen]).into_owned();
+        let password = buf[2 + ulen + 1..len].to_vec();
+        Ok(Decoded::Complete(UserPass { username, password }, len))
+    }
+}
+
+// The METHODS a greeting offers. Anything but SOCKS5 gets its first two bytes handed back so
+// the caller can tell what it was talking to.
+pub(crate) fn decode_greeting(buf: &[u8]) -> Result<Decoded<Vec<u8>>, Socks5Error> {
+    if buf.len() < 2 {
+        return Ok(Decoded::Incomplete(2 - buf.len()));
+    }
+    if buf[0] != SOCKS_VERSION {
+        return Err(Socks5Error::NotSocks(buf[..2].to_vec()));
+    }
+
+    let len = 2 + buf[1] as usize;
+    if buf.len() < len {
+        return Ok(Decoded::Incomplete(len - buf.len()));
+    }
+    Ok(Decoded::Complete(buf[2..len].to_vec(), len))
+}
+
+// The method a server picked from the greeting
+pub(crate) fn decode_method(buf: &[u8]) -> Result<Decoded<u8>, Socks5Error> {
+    if buf.len() < 2 {
+        return Ok(Decoded::Incomplete(2 - buf.len()));
+    }
+    if buf[0] != SOCKS_VERSION {
+        return Err(Socks5Error::UnsupportedVersion);
+    }
+    Ok(Decoded::Complete(buf[1], 2))
+}
+
+// Whether RFC 1929 sub-negotiation succeeded; its VER isn't checked, as not every server
+// gets it right
+pub(crate) fn decode_auth_status(buf: &[u8]) -> Decoded<bool> {
+    if buf.len() < 2 {
+        return Decoded::Incomplete(2 - buf.len());
+    }
+    Decoded::Complete(buf[1] == AUTH_SUCCESS, 2)
+}
+
+// ATYP, the address and the port, as requests, replies and UDP headers all carry them
+pub(crate) fn encode_address(addr: &Address, buf: &mut Vec<u8>) -> Result<(), Socks5Error> {
+    match addr {
+        Address::Ip(SocketAddr::V4(addr)) => {
+            buf.push(TYP_IPV4);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        Address::Ip(SocketAddr::V6(addr)) => {
+            buf.push(TYP_IPV6);
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        Address::Domain(domain, _) => {
+            if domain.len() > 0xff {
+                return Err(Socks5Error::ParseAddrError);
+            }
+            buf.push(TYP_DOMAIN);
+            buf.push(domain.len() as u8);
+            buf.extend_from_slice(domain.as_bytes());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+    Ok(())
+}
+
+// An address from the front of `buf`, and whatever follows it; None if it's cut short or
+// malformed
+pub(crate) fn decode_address(buf: &[u8]) -> Option<(Address, &[u8])> {
+    match decode_addr(buf) {
+        Ok(Decoded::Complete(addr, len)) => Some((addr, &buf[len..])),
+        _ => None,
+    }
+}
+
+fn decode_addr(buf: &[u8]) -> Result<Decoded<Address>, Socks5Error> {
+    let typ = match buf.first() {
+        Some(&typ) => typ,
+        None => return Ok(Decoded::Incomplete(1)),
+    };
+    let (start, len) = match typ {
+        TYP_IPV4 => (1, 4),
+        TYP_IPV6 => (1, 16),
+        TYP_DOMAIN => match buf.get(1) {
+            Some(&len) => (2, len as usize),
+            None => return Ok(Decoded::Incomplete(1)),
+        },
+        _ => return Err(Socks5Error::UnrecognizedAddrType),
+    };
+    let end = start + len + 2;
+    if buf.len() < end {
+        return Ok(Decoded::Incomplete(end - buf.len()));
+    }
+
+    let host = &buf[start..start + len];
+    let port = u16::from_be_bytes([buf[end - 2], buf[end - 1]]);
+    let addr = match typ {
+        TYP_IPV4 => {
+            let octets: [u8; 4] = host.try_into().map_err(|_| Socks5Error::ParseAddrError)?;
+            Address::from_ip(IpAddr::from(octets), port)
+        }
+        TYP_IPV6 => {
+            let octets: [u8; 16] = host.try_into().map_err(|_| Socks5Error::ParseAddrError)?;
+            Address::from_ip(IpAddr::from(octets), port)
+        }
+        _ => {
+            let domain =
+                String::from_utf8(host.to_vec()).map_err(|_| Socks5Error::ParseAddrError)?;
+            Address::Domain(domain, port)
+        }
+    };
+    Ok(Decoded::Complete(addr, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(bnd: Address) -> Reply {
+        Reply {
+            rep: RESP_SUCCESS,
+            bnd: Some(bnd),
+        }
+    }
+
+    #[test]
+    fn reply_encodes_ipv4() {
+        let buf = reply(Address::Ip("10.0.0.1:1080".parse().unwrap()))
+            .encode()
+            .unwrap();
+        assert_eq!(buf, [5, 0, 0, TYP_IPV4, 10, 0, 0, 1, 0x04, 0x38]);
+    }
+
+    #[test]
+    fn reply_encodes_ipv6() {
+        let buf = reply(Address::Ip("[::1]:80".parse().unwrap()))
+            .encode()
+            .unwrap();
+        let mut expected = vec![5, 0, 0, TYP_IPV6];
+        expected.extend_from_slice(&[0; 15]);
+        expected.extend_from_slice(&[1, 0, 80]);
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn reply_encodes_domain() {
+        let buf = reply(Address::Domain("example.com".to_string(), 443))
+            .encode()
+            .unwrap();
+        let mut expected = vec![5, 0, 0, TYP_DOMAIN, 11];
+        expected.extend_from_slice(b"example.com");
+        expected.extend_from_slice(&[0x01, 0xbb]);
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn reply_without_bnd_is_unspecified() {
+        let buf = Reply {
+            rep: RESP_NOT_ALLOWED,
+            bnd: None,
+        }
+        .encode()
+        .unwrap();
+        assert_eq!(buf, [5, RESP_NOT_ALLOWED, 0, TYP_IPV4, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn reply_rejects_oversized_domain() {
+        let res = reply(Address::Domain("a".repeat(256), 80)).encode();
+        assert!(matches!(res, Err(Socks5Error::ParseAddrError)));
+
+        // 255 bytes still fit the length byte
+        let buf = reply(Address::Domain("a".repeat(255), 80))
+            .encode()
+            .unwrap();
+        assert_eq!(buf.len(), 4 + 1 + 255 + 2);
+    }
+
+    #[test]
+    fn reply_with_truncated_domain_is_incomplete() {
+        let buf = reply(Address::Domain("example.com".to_string(), 443))
+            .encode()
+            .unwrap();
+        for cut in 0..buf.len() {
+            match Reply::decode(&buf[..cut]).unwrap() {
+                Decoded::Incomplete(more) => assert!(cut + more <= buf.len()),
+                Decoded::Complete(..) => panic!("decoded {} of {} bytes", cut, buf.len()),
+            }
+        }
+
+        match Reply::decode(&buf).unwrap() {
+            Decoded::Complete(decoded, len) => {
+                assert_eq!(len, buf.len());
+                assert_eq!(
+                    decoded.bnd,
+                    Some(Address::Domain("example.com".to_string(), 443))
+                );
+            }
+            Decoded::Incomplete(_) => panic!("whole reply not decoded"),
+        }
+    }
+}
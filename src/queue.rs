@@ -0,0 +1,221 @@
+use futures::channel::oneshot;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// A concurrency limit that lets priority connections jump the queue when it's full.
+// A freed slot is handed straight to the next waiter, so the count only drops when nobody waits.
+pub(crate) struct Gate {
+    limit: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    active: usize,
+    priority: VecDeque<oneshot::Sender<()>>,
+    normal: VecDeque<oneshot::Sender<()>>,
+}
+
+pub(crate) struct Permit<'a> {
+    gate: &'a Gate,
+}
+
+impl Gate {
+    pub(crate) fn new(limit: usize) -> Self {
+        Gate {
+            limit,
+            state: Mutex::new(State {
+                active: 0,
+                priority: VecDeque::new(),
+                normal: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub(crate) async fn acquire(&self, priority: bool) -> Permit<'_> {
+        let rx = {
+            let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+            if state.active < self.limit {
+                state.active += 1;
+                return Permit { gate: self };
+            }
+
+            let (tx, rx) = oneshot::channel();
+            if priority {
+                state.priority.push_back(tx);
+            } else {
+                state.normal.push_back(tx);
+            }
+            rx
+        };
+
+        let _ = rx.await;
+        Permit { gate: self }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let mut state = self
+            .gate
+            .state
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        loop {
+            let next = match state.priority.pop_front() {
+                Some(tx) => tx,
+                None => match state.normal.pop_front() {
+                    Some(tx) => tx,
+                    None => break,
+                },
+            };
+            // A waiter that went away doesn't get the slot
+            if next.send(()).is_ok() {
+                return;
+            }
+        }
+        state.active -= 1;
+    }
+}
+
+// Holds back accepting while paused. Each waiter is after one state or the other, and is
+// woken once the pause gets there.
+pub(crate) struct Pause {
+    state: Mutex<PauseState>,
+}
+
+struct PauseState {
+    paused: bool,
+    waiters: Vec<(bool, oneshot::Sender<()>)>,
+}
+
+impl Pause {
+    pub(crate) fn new() -> Self {
+        Pause {
+            state: Mutex::new(PauseState {
+                paused: false,
+                waiters: vec![],
+            }),
+        }
+    }
+
+    pub(crate) fn pause(&self) {
+        self.set(true);
+    }
+
+    pub(crate) fn resume(&self) {
+        self.set(false);
+    }
+
+    fn set(&self, paused: bool) {
+        let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.paused = paused;
+        let waiters = std::mem::take(&mut state.waiters);
+        for (want, tx) in waiters {
+            if want == paused {
+                let _ = tx.send(());
+            } else {
+                state.waiters.push((want, tx));
+            }
+        }
+    }
+
+    // Resolves once the pause is `paused`
+    pub(crate) async fn until(&self, paused: bool) {
+        let rx = {
+            let mut state = self.state.lock().unwrap_or_else(|err| err.into_inner());
+            if state.paused == paused {
+                return;
+            }
+            // Waiters given up on, like an accept's each time a connection comes in first
+            state.waiters.retain(|(_, tx)| !tx.is_canceled());
+            let (tx, rx) = oneshot::channel();
+            state.waiters.push((paused, tx));
+            rx
+        };
+        let _ = rx.await;
+    }
+}
+
+// A plain count of something in progress, turning away whatever would take it past a limit
+pub(crate) struct Limit {
+    active: AtomicUsize,
+}
+
+pub(crate) struct LimitPermit<'a> {
+    limit: &'a Limit,
+}
+
+impl Limit {
+    pub(crate) const fn new() -> Self {
+        Limit {
+            active: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn try_acquire(&self, max: usize) -> Option<LimitPermit<'_>> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                if active < max {
+                    Some(active + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| LimitPermit { limit: self })
+    }
+}
+
+impl Drop for LimitPermit<'_> {
+    fn drop(&mut self) {
+        self.limit.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// Caps how many connections a single source address may have open at once
+pub(crate) struct PerIpLimit {
+    limit: usize,
+    counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+pub(crate) struct IpPermit<'a> {
+    limiter: &'a PerIpLimit,
+    ip: IpAddr,
+}
+
+impl PerIpLimit {
+    pub(crate) fn new(limit: usize) -> Self {
+        PerIpLimit {
+            limit,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn try_acquire(&self, ip: IpAddr) -> Option<IpPermit<'_>> {
+        let mut counts = self.counts.lock().unwrap_or_else(|err| err.into_inner());
+        let count = counts.entry(ip).or_insert(0);
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(IpPermit { limiter: self, ip })
+    }
+}
+
+impl Drop for IpPermit<'_> {
+    fn drop(&mut self) {
+        let mut counts = self
+            .limiter
+            .counts
+            .lock()
+            .unwrap_or_else(|err| err.into_inner());
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use crate::config::Config;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Bounds the map; expired entries are dropped once it gets this big
+const MAX_ENTRIES: usize = 4096;
+
+static NAMES: Mutex<Option<HashMap<IpAddr, (String, Instant)>>> = Mutex::new(None);
+
+// Remembers that `ip` was the answer for `name`, for `--dns-reverse-map` seconds
+pub(crate) fn record(ip: IpAddr, name: &str, config: &Config) {
+    let window = match config.dns_reverse_map {
+        Some(window) => window,
+        None => return,
+    };
+
+    let mut names = NAMES.lock().unwrap_or_else(|err| err.into_inner());
+    let names = names.get_or_insert_with(HashMap::new);
+    let now = Instant::now();
+    if names.len() >= MAX_ENTRIES {
+        names.retain(|_, (_, expires)| *expires > now);
+    }
+    if names.len() < MAX_ENTRIES {
+        names.insert(ip, (name.trim_end_matches('.').to_string(), now + window));
+    }
+}
+
+// The name a client most likely had in mind when it asked for a bare IP
+pub(crate) fn lookup(ip: IpAddr) -> Option<String> {
+    let names = NAMES.lock().unwrap_or_else(|err| err.into_inner());
+    match names.as_ref()?.get(&ip) {
+        Some((name, expires)) if *expires > Instant::now() => Some(name.clone()),
+        _ => None,
+    }
+}
@@ -0,0 +1,73 @@
+use crate::{config::Config, errors::Socks5Error, policy::ExitPolicy, server::Stop};
+use futures::future::{self, Either};
+use std::time::{Duration, SystemTime};
+
+// How often --exit-policy-file and --users-file are checked for changes
+const POLL: Duration = Duration::from_secs(2);
+
+// Re-reads the exit policy and users files whenever they change, until the server stops.
+// A file that no longer parses is logged and the previous version stays in force.
+pub(crate) async fn watch(config: &Config, stop: Stop) {
+    let mut files = vec![];
+    if let Some(path) = &config.exit_policy_file {
+        files.push((path.as_str(), modified(path).await));
+    }
+    if let Some(path) = &config.users_file {
+        files.push((path.as_str(), modified(path).await));
+    }
+    if files.is_empty() {
+        return;
+    }
+
+    let mut stop = stop;
+    loop {
+        let tick = async_std::task::sleep(POLL);
+        futures::pin_mut!(tick);
+        if let Either::Right(_) = future::select(tick, &mut stop).await {
+            return;
+        }
+
+        for (path, last) in &mut files {
+            let now = modified(path).await;
+            if now.is_none() || now == *last {
+                continue;
+            }
+            *last = now;
+            match reload(path, config).await {
+                Ok(()) => crate::log::info(format_args!("reloaded {}", path)),
+                Err(err) => crate::log::warn(format_args!(
+                    "{}: {}, keeping what was loaded before",
+                    path, err
+                )),
+            }
+        }
+    }
+}
+
+async fn modified(path: &str) -> Option<SystemTime> {
+    async_std::fs::metadata(path).await.ok()?.modified().ok()
+}
+
+async fn reload(path: &str, config: &Config) -> Result<(), String> {
+    let content = async_std::fs::read_to_string(path)
+        .await
+        .map_err(|err| err.to_string())?;
+    if config.exit_policy_file.as_deref() == Some(path) {
+        let policy = ExitPolicy::parse(&content).map_err(message)?;
+        if let Some(current) = &config.exit_policy {
+            current.replace(policy);
+        }
+    }
+    if config.users_file.as_deref() == Some(path) {
+        let users = crate::config::parse_users_file(&content).map_err(message)?;
+        if let Some(current) = &config.file_users {
+            current.replace(users);
+        }
+    }
+    Ok(())
+}
+
+// The error without the `[Err] ` its Display starts with, as it's logged as a warning
+fn message(err: Socks5Error) -> String {
+    err.to_string().trim_start_matches("[Err] ").to_string()
+}
@@ -0,0 +1,204 @@
+use crate::{config::Config, errors::ConnError};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+static SHED: AtomicU64 = AtomicU64::new(0);
+static PANICS: AtomicU64 = AtomicU64::new(0);
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static ERRORS: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+static STARTED: Mutex<Option<Instant>> = Mutex::new(None);
+// Servers shedding load right now, and the rate limit window that came into force last
+static SHEDDING: AtomicUsize = AtomicUsize::new(0);
+static RATE_WINDOW: Mutex<Option<String>> = Mutex::new(None);
+
+// Where a server's metrics go as well as into the report, for embedders to route them
+// into their own telemetry. Hand one to `Builder::metrics`. Counters are `connections`,
+// `rejected`, `shed`, `panics`, `bytes_sent`, `bytes_received` and `errors.KIND`; the
+// gauge is `connections_open` and the histogram `connection_duration_secs`.
+pub trait MetricsSink: Send + Sync {
+    fn counter(&self, name: &str, value: u64);
+    fn gauge(&self, name: &str, value: f64);
+    fn histogram(&self, name: &str, value: f64);
+}
+
+pub(crate) fn record_connection(config: &Config) {
+    CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    count(config, "connections", 1);
+}
+
+// A connection turned away before the handshake by a connection limit
+pub(crate) fn record_rejection(config: &Config) {
+    REJECTED.fetch_add(1, Ordering::Relaxed);
+    count(config, "rejected", 1);
+}
+
+// A connection dropped by load shedding
+pub(crate) fn record_shed(config: &Config) {
+    SHED.fetch_add(1, Ordering::Relaxed);
+    count(config, "shed", 1);
+}
+
+// A connection task that panicked, caught before it could take the server down
+pub(crate) fn record_panic(config: &Config) {
+    PANICS.fetch_add(1, Ordering::Relaxed);
+    count(config, "panics", 1);
+}
+
+pub(crate) fn record_bytes(sent: u64, received: u64, config: &Config) {
+    BYTES_SENT.fetch_add(sent, Ordering::Relaxed);
+    BYTES_RECEIVED.fetch_add(received, Ordering::Relaxed);
+    count(config, "bytes_sent", sent);
+    count(config, "bytes_received", received);
+}
+
+pub(crate) fn record_error(err: &ConnError, config: &Config) {
+    *ERRORS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .entry(err.err.kind())
+        .or_insert(0) += 1;
+    if let Some(metrics) = &config.metrics {
+        metrics.counter(&format!("errors.{}", err.err.kind()), 1);
+    }
+}
+
+// How many of the server's connections are open, and how long the one that just closed lasted
+pub(crate) fn record_open(open: usize, closed_after: Option<Duration>, config: &Config) {
+    if let Some(metrics) = &config.metrics {
+        metrics.gauge("connections_open", open as f64);
+        if let Some(duration) = closed_after {
+            metrics.histogram("connection_duration_secs", duration.as_secs_f64());
+        }
+    }
+}
+
+pub(crate) fn record_shedding(shedding: bool) {
+    if shedding {
+        SHEDDING.fetch_add(1, Ordering::Relaxed);
+    } else {
+        SHEDDING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn record_window(window: Option<String>) {
+    *RATE_WINDOW.lock().unwrap_or_else(|err| err.into_inner()) = window;
+}
+
+fn count(config: &Config, name: &str, value: u64) {
+    if let Some(metrics) = &config.metrics {
+        metrics.counter(name, value);
+    }
+}
+
+// Starts the uptime clock and, on the first SIGINT/SIGTERM, calls `on_signal` to start a graceful
+// shutdown; a second one logs the report (writing it to `path` as JSON) and exits right away.
+// SIGUSR1 logs the open tunnels whenever it comes, SIGUSR2 writes the `--snapshot` file.
+// Has to run before any other thread is spawned so they all inherit the blocked signals.
+pub fn install(path: Option<String>, on_signal: impl FnOnce() + Send + 'static) {
+    *STARTED.lock().unwrap_or_else(|err| err.into_inner()) = Some(Instant::now());
+
+    #[cfg(unix)]
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGUSR1);
+        libc::sigaddset(&mut set, libc::SIGUSR2);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+
+        std::thread::spawn(move || {
+            let mut on_signal = Some(on_signal);
+            loop {
+                let mut sig = 0;
+                libc::sigwait(&set, &mut sig);
+                if sig == libc::SIGUSR1 {
+                    crate::tunnels::log_listing();
+                    continue;
+                }
+                if sig == libc::SIGUSR2 {
+                    crate::tunnels::write_snapshot();
+                    continue;
+                }
+                match on_signal.take() {
+                    Some(on_signal) => {
+                        crate::log::info("shutting down, no longer accepting connections");
+                        on_signal();
+                    }
+                    None => {
+                        finish(path.as_deref());
+                        std::process::exit(0);
+                    }
+                }
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    let _ = (path, on_signal);
+}
+
+// Logs the report, and writes it to `path` as JSON
+pub fn finish(path: Option<&str>) {
+    let uptime = STARTED
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .map_or(0, |started| started.elapsed().as_secs());
+    let connections = CONNECTIONS.load(Ordering::Relaxed);
+    let rejected = REJECTED.load(Ordering::Relaxed);
+    let shed = SHED.load(Ordering::Relaxed);
+    let shedding = SHEDDING.load(Ordering::Relaxed) > 0;
+    let panics = PANICS.load(Ordering::Relaxed);
+    let sent = BYTES_SENT.load(Ordering::Relaxed);
+    let received = BYTES_RECEIVED.load(Ordering::Relaxed);
+    let errors = ERRORS.lock().unwrap_or_else(|err| err.into_inner()).clone();
+    let window = RATE_WINDOW
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .clone();
+
+    let by_kind = errors
+        .iter()
+        .map(|(kind, count)| format!("{} {}", kind, count))
+        .collect::<Vec<_>>()
+        .join(", ");
+    crate::log::info(format_args!(
+        "shutting down after {}s: {} connections, {} rejected, {} shed{}, {} panicked, {} bytes sent, {} bytes received, errors: {}{}",
+        uptime,
+        connections,
+        rejected,
+        shed,
+        if shedding { " (still shedding)" } else { "" },
+        panics,
+        sent,
+        received,
+        if by_kind.is_empty() { "none" } else { &by_kind },
+        match &window {
+            Some(window) => format!(", rate limit window {}", window),
+            None => String::new(),
+        }
+    ));
+
+    if let Some(path) = path {
+        let errors = errors
+            .iter()
+            .map(|(kind, count)| format!("\"{}\":{}", kind, count))
+            .collect::<Vec<_>>()
+            .join(",");
+        let window = match window {
+            Some(window) => format!("\"{}\"", window),
+            None => "null".to_string(),
+        };
+        let json = format!(
+            "{{\"uptime_secs\":{},\"connections\":{},\"rejected\":{},\"shed\":{},\"shedding\":{},\"panics\":{},\"bytes_sent\":{},\"bytes_received\":{},\"errors\":{{{}}},\"rate_window\":{}}}\n",
+            uptime, connections, rejected, shed, shedding, panics, sent, received, errors, window
+        );
+        if let Err(err) = std::fs::write(path, json) {
+            crate::log::err(format_args!("writing report to {}: {}", path, err));
+        }
+    }
+}
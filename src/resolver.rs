@@ -0,0 +1,354 @@
+use crate::config::Config;
+use async_std::{
+    io,
+    net::{TcpStream, UdpSocket},
+    prelude::*,
+};
+use dns_lookup::{AddrInfoHints, LookupErrorKind};
+use futures::future::{self, BoxFuture, FutureExt};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+// Turns domain names from CONNECT requests into addresses. Implement it to plug in
+// your own DNS client and hand it to `Builder::resolver`; `--dns-timeout` and
+// `--dns-retries` apply on top of any implementation, and concurrent lookups of one name
+// are shared.
+pub trait Resolver: Send + Sync {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>>;
+}
+
+// The system resolver, called on the blocking thread pool so it doesn't stall the executor
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        let name = host.to_string();
+        blocking::unblock(move || getaddrinfo(&name)).boxed()
+    }
+}
+
+// What a lookup came to, in a form every waiter on it can have a copy of
+type Shared = future::Shared<BoxFuture<'static, Result<Vec<IpAddr>, (io::ErrorKind, String)>>>;
+
+// Lookups in progress, keyed by resolver and name. Clients asking for a name that's
+// already being looked up wait on that lookup rather than starting their own, so a burst
+// of requests for one name costs a single query.
+static IN_FLIGHT: Mutex<Option<HashMap<(usize, String), Shared>>> = Mutex::new(None);
+
+pub(crate) async fn lookup_host(host: &str, config: &Config) -> io::Result<Vec<IpAddr>> {
+    // Servers embedded side by side may have different resolvers
+    let key = (
+        Arc::as_ptr(&config.resolver) as *const () as usize,
+        host.to_string(),
+    );
+
+    let lookup = {
+        let mut in_flight = IN_FLIGHT.lock().unwrap_or_else(|err| err.into_inner());
+        let in_flight = in_flight.get_or_insert_with(HashMap::new);
+        match in_flight.get(&key) {
+            Some(lookup) => lookup.clone(),
+            None => {
+                let lookup = start_lookup(key.clone(), config);
+                in_flight.insert(key, lookup.clone());
+                lookup
+            }
+        }
+    };
+
+    lookup
+        .await
+        .map_err(|(kind, msg)| io::Error::new(kind, msg))
+}
+
+// Owns all it needs, as it carries on for the other waiters when the one that started it
+// goes away
+fn start_lookup(key: (usize, String), config: &Config) -> Shared {
+    let resolver = config.resolver.clone();
+    let dns_timeout = config.dns_timeout;
+    let mut retries = config.dns_retries;
+
+    async move {
+        let res = loop {
+            let lookup = resolver.lookup(&key.1);
+
+            let res = match dns_timeout {
+                Some(dns_timeout) => io::timeout(dns_timeout, lookup).await,
+                None => lookup.await,
+            };
+
+            match res {
+                Err(err) if retries > 0 && is_transient(&err) => retries -= 1,
+                res => break res,
+            }
+        };
+
+        // Anyone asking from now on gets a fresh answer
+        if let Some(in_flight) = IN_FLIGHT
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .as_mut()
+        {
+            in_flight.remove(&key);
+        }
+        res.map_err(|err| (err.kind(), err.to_string()))
+    }
+    .boxed()
+    .shared()
+}
+
+// The name `ip` points back to, for RESOLVE_PTR; an address without one is an error
+// rather than coming back as itself
+pub(crate) async fn lookup_addr(ip: IpAddr, config: &Config) -> io::Result<String> {
+    let lookup = blocking::unblock(move || {
+        let flags = libc::NI_NUMERICSERV | libc::NI_NAMEREQD;
+        dns_lookup::getnameinfo(&(ip, 0).into(), flags)
+            .map(|(name, _)| name)
+            .map_err(io::Error::from)
+    });
+    match config.dns_timeout {
+        Some(dns_timeout) => io::timeout(dns_timeout, lookup).await,
+        None => lookup.await,
+    }
+}
+
+// Same query `dns_lookup::lookup_host` makes, but keeping EAI_AGAIN distinguishable
+fn getaddrinfo(host: &str) -> io::Result<Vec<IpAddr>> {
+    let hints = AddrInfoHints {
+        socktype: socket2::Type::stream().into(),
+        ..AddrInfoHints::default()
+    };
+
+    match dns_lookup::getaddrinfo(Some(host), None, Some(hints)) {
+        Ok(addrs) => addrs.map(|r| r.map(|a| a.sockaddr.ip())).collect(),
+        Err(err) if matches!(err.kind(), LookupErrorKind::Again) => Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "temporary resolver failure",
+        )),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+    )
+}
+
+// Looks names up itself rather than through getaddrinfo, which never lets on that an answer
+// came back truncated: /etc/hosts first, then the nameservers in /etc/resolv.conf over UDP,
+// asking again over TCP when an answer didn't fit. `--dns-tcp-fallback` resolves with this.
+pub struct TcpFallbackResolver;
+
+impl Resolver for TcpFallbackResolver {
+    fn lookup<'a>(&'a self, host: &'a str) -> BoxFuture<'a, io::Result<Vec<IpAddr>>> {
+        stub_lookup(host).boxed()
+    }
+}
+
+// How long a nameserver gets to answer over UDP before the next one is tried, as the
+// resolv.conf default
+const UDP_WAIT: Duration = Duration::from_secs(5);
+
+async fn stub_lookup(host: &str) -> io::Result<Vec<IpAddr>> {
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(vec![ip]);
+    }
+    let hosts = async_std::fs::read_to_string("/etc/hosts")
+        .await
+        .unwrap_or_default();
+    let ips = hosts_entries(&hosts, host);
+    if !ips.is_empty() {
+        return Ok(ips);
+    }
+
+    let resolv_conf = async_std::fs::read_to_string("/etc/resolv.conf").await?;
+    let nameservers = resolv_conf.lines().filter_map(|line| {
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("nameserver"), Some(ip)) => ip.parse::<IpAddr>().ok(),
+            _ => None,
+        }
+    });
+
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "no nameservers");
+    for nameserver in nameservers {
+        match query(SocketAddr::new(nameserver, 53), host).await {
+            Ok(ips) => return Ok(ips),
+            // The name doesn't exist, which the next nameserver won't change
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Err(err),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+// The addresses /etc/hosts gives `host`
+fn hosts_entries(hosts: &str, host: &str) -> Vec<IpAddr> {
+    let host = host.trim_end_matches('.');
+    hosts
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split('#').next()?.split_whitespace();
+            let ip = words.next()?.parse::<IpAddr>().ok()?;
+            words
+                .any(|name| name.eq_ignore_ascii_case(host))
+                .then_some(ip)
+        })
+        .collect()
+}
+
+async fn query(nameserver: SocketAddr, host: &str) -> io::Result<Vec<IpAddr>> {
+    let mut ips = vec![];
+    for qtype in [TYPE_A, TYPE_AAAA] {
+        let id = query_id();
+        let query = dns_query(id, host, qtype)?;
+        let answer = match query_udp(nameserver, &query).await? {
+            answer if is_truncated(&answer) => query_tcp(nameserver, &query).await?,
+            answer => answer,
+        };
+        ips.extend(dns_answer(id, &answer)?);
+    }
+
+    if ips.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no addresses for {}", host),
+        ));
+    }
+    Ok(ips)
+}
+
+// Unpredictable, so an off-path answer can't be made to match
+fn query_id() -> u16 {
+    use std::hash::{BuildHasher, Hasher};
+    std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish() as u16
+}
+
+async fn query_udp(nameserver: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let local: SocketAddr = match nameserver {
+        SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    // Connected, so only the nameserver's datagrams come in
+    socket.connect(nameserver).await?;
+    socket.send(query).await?;
+    let mut buf = vec![0u8; 512];
+    let n = io::timeout(UDP_WAIT, socket.recv(&mut buf)).await?;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+async fn query_tcp(nameserver: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(nameserver).await?;
+    stream
+        .write_all(&(query.len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(query).await?;
+
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await?;
+    let mut answer = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut answer).await?;
+    Ok(answer)
+}
+
+// TC, set when the answer was cut short to fit a UDP datagram
+fn is_truncated(answer: &[u8]) -> bool {
+    answer.get(2).is_some_and(|flags| flags & 0x02 != 0)
+}
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+// A recursive query for `host`: header, then the question
+fn dns_query(id: u16, host: &str, qtype: u16) -> io::Result<Vec<u8>> {
+    let mut buf = vec![];
+    buf.extend_from_slice(&id.to_be_bytes());
+    // RD, with one question
+    buf.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid name {}", host),
+            ));
+        }
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    buf.push(0);
+    buf.extend_from_slice(&qtype.to_be_bytes());
+    buf.extend_from_slice(&CLASS_IN.to_be_bytes());
+    Ok(buf)
+}
+
+// The A and AAAA records among the answers; a CNAME's target records follow it there
+fn dns_answer(id: u16, buf: &[u8]) -> io::Result<Vec<IpAddr>> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed DNS answer");
+    let u16_at = |pos: usize| -> io::Result<u16> {
+        match buf.get(pos..pos + 2) {
+            Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+            None => Err(malformed()),
+        }
+    };
+
+    if u16_at(0)? != id {
+        return Err(malformed());
+    }
+    match u16_at(2)? & 0xf {
+        0 => (),
+        // NXDOMAIN
+        3 => return Ok(vec![]),
+        rcode => {
+            return Err(io::Error::other(format!("DNS server error {}", rcode)));
+        }
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(buf, pos).ok_or_else(malformed)? + 4;
+    }
+    let mut ips = vec![];
+    for _ in 0..answers {
+        pos = skip_name(buf, pos).ok_or_else(malformed)?;
+        let (rtype, class, len) = (u16_at(pos)?, u16_at(pos + 2)?, u16_at(pos + 8)? as usize);
+        let data = buf.get(pos + 10..pos + 10 + len).ok_or_else(malformed)?;
+        if class == CLASS_IN {
+            match rtype {
+                TYPE_A => ips.push(IpAddr::from(
+                    <[u8; 4]>::try_from(data).map_err(|_| malformed())?,
+                )),
+                TYPE_AAAA => ips.push(IpAddr::from(
+                    <[u8; 16]>::try_from(data).map_err(|_| malformed())?,
+                )),
+                _ => (),
+            }
+        }
+        pos += 10 + len;
+    }
+    Ok(ips)
+}
+
+// Where the name starting at `pos` ends, a compression pointer ending it on the spot
+fn skip_name(buf: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(pos)? as usize;
+        match len {
+            0 => return Some(pos + 1),
+            _ if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            _ => pos += 1 + len,
+        }
+    }
+}
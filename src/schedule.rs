@@ -0,0 +1,156 @@
+use crate::server::Stop;
+use async_std::task;
+use futures::future::{self, Either};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+const NONE: u64 = u64::MAX;
+
+// The rate limit window a server has in force
+pub(crate) struct Schedule {
+    // In bytes per second: 0 for unlimited, NONE when no window covers it
+    active: AtomicU64,
+    window: Mutex<Option<String>>,
+}
+
+// A time of day range (local time, minutes since midnight) with its own rate limit;
+// `start > end` wraps past midnight
+#[derive(Debug, Clone)]
+pub struct RateWindow {
+    start: u32,
+    end: u32,
+    // Bytes per second, None for unlimited
+    rate: Option<u64>,
+}
+
+impl RateWindow {
+    // Accepts `HH:MM-HH:MM=KB`, KB per second with 0 meaning unlimited
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        let (range, rate) = s.split_once('=')?;
+        let (start, end) = range.split_once('-')?;
+        let rate = rate.parse::<u64>().ok()?;
+        Some(RateWindow {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+            rate: if rate == 0 {
+                None
+            } else {
+                Some(rate.checked_mul(1024)?)
+            },
+        })
+    }
+
+    fn contains(&self, minute: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl std::fmt::Display for RateWindow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+fn parse_time(s: &str) -> Option<u32> {
+    let (h, m) = s.split_once(':')?;
+    let (h, m) = (h.parse::<u32>().ok()?, m.parse::<u32>().ok()?);
+    if h < 24 && m < 60 {
+        Some(h * 60 + m)
+    } else {
+        None
+    }
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            active: AtomicU64::new(NONE),
+            window: Mutex::new(None),
+        }
+    }
+}
+
+impl Schedule {
+    // Picks the window for the current time and keeps switching as the clock moves on,
+    // until the server stops
+    pub(crate) async fn run(&self, windows: &[RateWindow], stop: Stop) {
+        if windows.is_empty() {
+            return;
+        }
+        self.update(windows);
+
+        let mut stop = stop;
+        loop {
+            let tick = task::sleep(Duration::from_secs(60 - local_time().1 as u64));
+            futures::pin_mut!(tick);
+            if let Either::Right(_) = future::select(tick, &mut stop).await {
+                return;
+            }
+            self.update(windows);
+        }
+    }
+
+    fn update(&self, windows: &[RateWindow]) {
+        let minute = local_time().0;
+        let window = windows.iter().find(|window| window.contains(minute));
+        let name = window.map(|window| window.to_string());
+
+        let mut active = self.window.lock().unwrap_or_else(|err| err.into_inner());
+        if *active != name {
+            match window {
+                Some(window) => crate::log::info(format_args!(
+                    "rate limit window {} now active: {}",
+                    window,
+                    match window.rate {
+                        Some(rate) => format!("{} KB/s", rate / 1024),
+                        None => "unlimited".to_string(),
+                    }
+                )),
+                None => crate::log::info("no rate limit window active"),
+            }
+            crate::report::record_window(name.clone());
+        }
+        self.active.store(
+            window.map_or(NONE, |window| window.rate.unwrap_or(0)),
+            Ordering::Relaxed,
+        );
+        *active = name;
+    }
+
+    // The rate for a tunnel whose own limit is `default`, None when it shouldn't be paced
+    pub(crate) fn rate(&self, default: Option<u64>) -> Option<u64> {
+        match self.active.load(Ordering::Relaxed) {
+            NONE => default,
+            0 => None,
+            rate => Some(rate),
+        }
+    }
+}
+
+// Minutes since local midnight, and the second within the minute
+fn local_time() -> (u32, u32) {
+    #[cfg(unix)]
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        if !libc::localtime_r(&now, &mut tm).is_null() {
+            return ((tm.tm_hour * 60 + tm.tm_min) as u32, tm.tm_sec as u32);
+        }
+    }
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    (((secs / 60) % (24 * 60)) as u32, (secs % 60) as u32)
+}
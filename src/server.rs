@@ -1,164 +1,1183 @@
-use crate::errors::Socks5Error;
+use crate::{
+    address::Address,
+    auth::AuthResult,
+    config::{Config, DnsMode, ErrorMode},
+    errors::{ConnContext, ConnError, Phase, Protocol, Socks5Error},
+    handshake::{self, ServerEvent},
+    ioutil::{copy_bidirectional, CopyOptions, Stream, Traced},
+    policy::DenyAction,
+    protocol::{
+        Reply, Request, UserPass, CMD_BIND, CMD_CONNECT, CMD_RESOLVE, CMD_RESOLVE_PTR,
+        CMD_UDP_ASSOCIATE, NO_ACCEPTABLE, NO_AUTH, RESP_ADDR_NOT_SUPPORTED, RESP_CMD_NOT_SUPPORTED,
+        RESP_CONNECTION_REFUSED, RESP_GENERAL_FAILURE, RESP_HOST_UNREACHABLE,
+        RESP_NETWORK_UNREACHABLE, RESP_NOT_ALLOWED, RESP_SUCCESS, RESP_TTL_EXPIRED, USER_PASS,
+    },
+    queue::{Gate, Limit, LimitPermit, Pause, PerIpLimit, Permit},
+};
 use async_std::{
-    io,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    io::{Read, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket},
     prelude::*,
-    task,
 };
-use futures::stream::StreamExt;
+use futures::{
+    channel::oneshot,
+    future::{self, BoxFuture, Either, Shared},
+    stream::StreamExt,
+};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tracing::Instrument;
+
+// The caps on a server's connections, created once and shared by all its listeners and
+// acceptor threads so they hold for the server as a whole
+pub(crate) struct Limits {
+    // --max-connections
+    connections: Option<Gate>,
+    per_ip: Option<PerIpLimit>,
+    // --max-handshakes and --max-tunnels
+    handshakes: Limit,
+    tunnels: Limit,
+    pause: Arc<Pause>,
+    // Connections being served, for the `connections_open` gauge
+    open: AtomicUsize,
+}
+
+impl Limits {
+    fn new(config: &Config, pause: Arc<Pause>) -> Self {
+        Limits {
+            connections: match config.max_connections {
+                0 => None,
+                max => Some(Gate::new(max)),
+            },
+            per_ip: config.max_connections_per_ip.map(PerIpLimit::new),
+            handshakes: Limit::new(),
+            tunnels: Limit::new(),
+            pause,
+            open: AtomicUsize::new(0),
+        }
+    }
+}
+
+// What a server keeps track of while it runs, apart from any other server in the process
+#[derive(Default)]
+pub(crate) struct ServerState {
+    pub(crate) schedule: crate::schedule::Schedule,
+    pub(crate) shed: crate::shed::Shed,
+    pub(crate) breakers: crate::breaker::Breakers,
+    pub(crate) bindings: crate::binding::Bindings,
+    pub(crate) tunnels: Arc<crate::tunnels::Tunnels>,
+}
 
-const SOCKS_VERSION: u8 = 0x5;
-const NO_AUTH: u8 = 0x0;
-const RSV: u8 = 0x0;
-const CMD_CONNECT: u8 = 0x1;
-const TYP_IPV4: u8 = 0x1;
-const TYP_DOMAIN: u8 = 0x3;
-const TYP_IPV6: u8 = 0x4;
-const RESP_SUCCESS: u8 = 0x0;
+// Where a request ended up: a TCP connection with the BND its reply carries, a UDP relay
+// socket for UDP ASSOCIATE, or just the answer to a RESOLVE or RESOLVE_PTR
+enum Outbound {
+    Tcp(TcpStream, Option<Address>),
+    Udp(UdpSocket),
+    Resolved(Address),
+}
 
-async fn socks5_handshake(mut stream: &TcpStream) -> Result<Vec<SocketAddr>, Socks5Error> {
-    let mut buf = [0u8; 0xff];
+// A client has to open with the configured secret before anything is read as SOCKS
+async fn socks5_preamble<S: Read + Unpin>(
+    stream: &mut S,
+    preamble: &[u8],
+) -> Result<(), Socks5Error> {
+    let mut buf = vec![0u8; preamble.len()];
+    stream.read_exact(&mut buf).await?;
 
-    stream.read_exact(&mut buf[..2]).await?;
-    if buf[0] != SOCKS_VERSION {
-        return Err(Socks5Error::UnsupportedVersion);
+    if !crate::ioutil::constant_time_eq(&buf, preamble) {
+        return Err(Socks5Error::BadPreamble(buf));
     }
 
-    let nmethod = buf[1] as usize;
-    stream.read_exact(&mut buf[..nmethod]).await?;
+    Ok(())
+}
+
+// Method negotiation, starting from the `head` already read; returns the method chosen
+async fn socks5_handshake<S: Read + Write + Unpin>(
+    stream: &mut S,
+    handshake: &mut handshake::Server,
+    head: [u8; 2],
+    config: &Config,
+) -> Result<u8, Socks5Error> {
+    let methods = match handshake::next_event(stream, handshake, &head).await {
+        ServerEvent::Methods(methods) => methods,
+        ServerEvent::Error(err) => return Err(err),
+        _ => return Err(out_of_order()),
+    };
 
-    stream.write_all(&[SOCKS_VERSION, NO_AUTH]).await?;
+    let private = config
+        .auth_methods
+        .iter()
+        .map(|(id, _)| *id)
+        .find(|id| methods.contains(id));
+    let method = if let Some(id) = private {
+        id
+    } else if !config.authenticator.wants_user_pass() {
+        NO_AUTH
+    } else if methods.contains(&USER_PASS) {
+        USER_PASS
+    } else {
+        NO_ACCEPTABLE
+    };
+    stream.write_all(&handshake.choose_method(method)).await?;
+    match method {
+        NO_ACCEPTABLE => Err(Socks5Error::NoAcceptableMethod),
+        _ => Ok(method),
+    }
+}
 
-    stream.read_exact(&mut buf[..4]).await?;
-    if buf[0] != SOCKS_VERSION {
-        return Err(Socks5Error::UnsupportedVersion);
+// A private method's own sub-negotiation, returning who the client turned out to be
+async fn socks5_private_auth<S: Read + Write + Unpin + Send>(
+    stream: &mut S,
+    method: u8,
+    config: &Config,
+) -> Result<String, Socks5Error> {
+    let handler = config
+        .auth_methods
+        .iter()
+        .find(|(id, _)| *id == method)
+        .map(|(_, handler)| handler.clone())
+        .ok_or(Socks5Error::NoAcceptableMethod)?;
+    match handler.negotiate(stream).await? {
+        Some(user) => Ok(user),
+        None => Err(Socks5Error::AuthFailed(format!(
+            "auth method {:#04x}",
+            method
+        ))),
     }
-    if buf[1] != CMD_CONNECT {
-        return Err(Socks5Error::UnsupportedCommand);
+}
+
+// RFC 1929 sub-negotiation, returning the authenticated username and, with
+// --upstream-hop-tag, the hops a chaining proxy tagged it with
+async fn socks5_auth<S: Read + Write + Unpin>(
+    stream: &mut S,
+    handshake: &mut handshake::Server,
+    peer: Option<IpAddr>,
+    config: &Config,
+) -> Result<(String, u32), Socks5Error> {
+    let UserPass { username, password } = match handshake::next_event(stream, handshake, &[]).await
+    {
+        ServerEvent::Auth(creds) => creds,
+        ServerEvent::Error(err) => return Err(err),
+        _ => return Err(out_of_order()),
+    };
+
+    let (username, hops) = match config.upstream_hop_tag {
+        true => crate::upstream::split_hops(username),
+        false => (username, 0),
+    };
+    let checked = check_credentials(&username, &password, peer, config).await;
+    stream
+        .write_all(&handshake.auth_result(checked.is_ok()))
+        .await?;
+    checked.map(|_| (username, hops))
+}
+
+// The request that ends the handshake
+async fn socks5_request<S: Read + Unpin>(
+    stream: &mut S,
+    handshake: &mut handshake::Server,
+) -> Result<Request, Socks5Error> {
+    match handshake::next_event(stream, handshake, &[]).await {
+        ServerEvent::Request(request) => Ok(request),
+        ServerEvent::Error(err) => Err(err),
+        _ => Err(out_of_order()),
     }
+}
+
+// The machine only answers with what it was asked to decode, so this can't happen
+fn out_of_order() -> Socks5Error {
+    std::io::Error::other("handshake out of order").into()
+}
 
-    let host: Vec<IpAddr>;
-    match buf[3] {
-        TYP_IPV4 => {
-            stream.read_exact(&mut buf[..4]).await?;
-            if let Ok(bs) = crate::ioutil::try_into_wrapper::<&[u8], [u8; 4]>(&buf[..4]) {
-                host = vec![IpAddr::V4(Ipv4Addr::from(bs))];
-            } else {
-                return Err(Socks5Error::ParseAddrError);
+// Whether `username` may log in with `password` from `peer`, whichever protocol carried them
+pub(crate) async fn check_credentials(
+    username: &str,
+    password: &[u8],
+    peer: Option<IpAddr>,
+    config: &Config,
+) -> Result<(), Socks5Error> {
+    if let AuthResult::Failure = config.authenticator.authenticate(username, password).await {
+        return Err(Socks5Error::AuthFailed(username.to_string()));
+    }
+    if let Some(peer) = peer {
+        if !crate::binding::check(username, peer, config) {
+            return Err(Socks5Error::UserBound(username.to_string()));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn socks5_resolve(
+    target: &Address,
+    config: &Config,
+) -> Result<Vec<SocketAddr>, Socks5Error> {
+    let mut addrs: Vec<SocketAddr> = match target {
+        Address::Ip(addr) => vec![*addr],
+        Address::Domain(domain, port) => {
+            let (domain, mut ips) = socks5_lookup_searched(domain, config).await?;
+            let domain = domain.as_str();
+            for ip in &ips {
+                crate::rdns::record(*ip, domain, config);
+            }
+
+            // A public name answering with an internal address is a DNS rebinding attempt
+            if config.rebind_protection && !rebind_allowed(domain, config) {
+                ips.retain(|ip| !crate::ioutil::is_internal(ip, config.nat64_prefix));
+                if ips.is_empty() {
+                    return Err(Socks5Error::ConnectionNotAllowed);
+                }
             }
+            ips.into_iter()
+                .map(|ip| SocketAddr::new(ip, *port))
+                .collect()
         }
+    };
 
-        TYP_DOMAIN => {
-            stream.read_exact(&mut buf[..1]).await?;
-            let domain_len = buf[0] as usize;
+    // Checked on what a name resolved to rather than the name, so rebinding can't get past it
+    if config.deny_private {
+        addrs.retain(|addr| !crate::ioutil::is_internal(&addr.ip(), config.nat64_prefix));
+        if addrs.is_empty() {
+            return Err(Socks5Error::ConnectionNotAllowed);
+        }
+    }
+    Ok(addrs)
+}
 
-            stream.read_exact(&mut buf[..domain_len]).await?;
-            if let Ok(tmp_host) = String::from_utf8(buf[..domain_len].to_vec()) {
-                host = dns_lookup::lookup_host(&tmp_host)?;
-            } else {
-                return Err(Socks5Error::ParseAddrError);
+// The first name the search list makes of `domain` that resolves, with its addresses;
+// each is checked against the domain lists on its own
+async fn socks5_lookup_searched(
+    domain: &str,
+    config: &Config,
+) -> Result<(String, Vec<IpAddr>), Socks5Error> {
+    let mut last_err = Socks5Error::ConnectionNotAllowed;
+    for name in crate::domains::search_names(domain, config) {
+        if !crate::domains::allowed(&name, config) {
+            continue;
+        }
+        match crate::resolver::lookup_host(&name, config).await {
+            Ok(ips) => return Ok((name, ips)),
+            Err(err) => last_err = err.into(),
+        }
+    }
+    Err(last_err)
+}
+
+// The allowlist covers each listed domain and everything under it
+fn rebind_allowed(domain: &str, config: &Config) -> bool {
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    config.rebind_allow.iter().any(|allowed| {
+        domain == *allowed
+            || (domain.ends_with(allowed.as_str())
+                && domain[..domain.len() - allowed.len()].ends_with('.'))
+    })
+}
+
+// Every reply is framed the same way, only REP and BND differ; without a BND to tell,
+// it goes out as 0.0.0.0:0
+async fn socks5_reply<S: Read + Write + Unpin>(
+    stream: &mut S,
+    rep: u8,
+    bnd: Option<Address>,
+) -> Result<(), std::io::Error> {
+    match (Reply { rep, bnd }).encode() {
+        Ok(buf) => stream.write_all(&buf).await,
+        Err(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "BND.ADDR too long",
+        )),
+    }
+}
+
+// The success reply in whichever protocol the client spoke, 0.0.0.0:0 standing in for
+// a missing BND
+async fn reply_success<S: Read + Write + Unpin>(
+    stream: &mut S,
+    ctx: &ConnContext,
+    bnd: Option<Address>,
+) -> Result<(), std::io::Error> {
+    match ctx.protocol {
+        Protocol::Socks5 => socks5_reply(stream, RESP_SUCCESS, bnd).await,
+        Protocol::Socks4 => {
+            let bnd = match bnd {
+                Some(Address::Ip(addr)) => Some(addr),
+                _ => None,
+            };
+            crate::socks4::reply(stream, crate::socks4::RESP_GRANTED, bnd).await
+        }
+        Protocol::Http => crate::http::reply_success(stream).await,
+    }
+}
+
+async fn socks5_relay<S: Stream>(
+    local: S,
+    remote: TcpStream,
+    stats: Option<&crate::tunnels::TunnelStats>,
+    config: &Config,
+) -> Result<(u64, u64), std::io::Error> {
+    #[cfg(target_os = "linux")]
+    {
+        // Splicing never sees the bytes, so it can't count or pace them or notice idleness
+        if config.relay == crate::config::RelayStrategy::Splice
+            && stats.is_none()
+            && config.rate_limit.is_none()
+            && config.rate_windows.is_empty()
+            && config.idle_timeout.is_none()
+            && crate::splice::supported()
+        {
+            use std::os::unix::io::AsRawFd;
+            return crate::splice::copy_bidirectional(
+                local.as_raw_fd(),
+                remote.as_raw_fd(),
+                config.stall_timeout,
+            )
+            .await;
+        }
+    }
+
+    let opts = CopyOptions {
+        stall_timeout: config.stall_timeout,
+        idle_timeout: config.idle_timeout,
+        rate_limit: config.rate_limit,
+        schedule: if config.rate_windows.is_empty() {
+            None
+        } else {
+            Some(&config.state.schedule)
+        },
+        stats,
+    };
+    copy_bidirectional(local, remote, opts).await
+}
+
+async fn socks5_reply_failure<S: Read + Write + Unpin>(
+    stream: &mut S,
+    rep: u8,
+) -> Result<(), std::io::Error> {
+    socks5_reply(stream, rep, None).await
+}
+
+// Every address a CONNECT could use to reach this very listener
+pub(crate) fn self_addrs(listen_addr: SocketAddr, config: &Config) -> Vec<SocketAddr> {
+    let mut ips = config.self_addrs.clone();
+    ips.push(listen_addr.ip());
+    if listen_addr.ip().is_unspecified() {
+        ips.extend(crate::ioutil::local_ips());
+    }
+
+    ips.into_iter()
+        .map(|ip| SocketAddr::new(ip, listen_addr.port()))
+        .collect()
+}
+
+async fn handle_connection<S: Stream>(
+    stream: S,
+    ctx: ConnContext,
+    config: &Config,
+    limits: &Limits,
+    self_addrs: &[SocketAddr],
+) {
+    // Scanners open plenty of connections that never get past negotiating, so those are
+    // turned away outright rather than held against the tunnel limit
+    let handshake = match config.max_handshakes {
+        Some(max) => match limits.handshakes.try_acquire(max) {
+            Some(permit) => Some(permit),
+            None => {
+                crate::report::record_rejection(config);
+                return;
+            }
+        },
+        None => None,
+    };
+    let id = ctx.id;
+    let started = std::time::Instant::now();
+    crate::report::record_connection(config);
+    let open = limits.open.fetch_add(1, Ordering::Relaxed) + 1;
+    crate::report::record_open(open, None, config);
+
+    let span = crate::log::conn_span(&ctx);
+    let serve = serve_connection(stream, ctx, handshake, config, limits, self_addrs)
+        .instrument(span.clone());
+    match crate::isolate::catch_panic(serve).await {
+        Ok(Ok(ctx)) => crate::log::conn_closed(&span, &ctx, started.elapsed()),
+        Ok(Err(err)) => {
+            if let Phase::Handshake | Phase::Auth = err.phase {
+                crate::shed::record_failure(config);
             }
+            crate::report::record_error(&err, config);
+            crate::log::conn_error(&span, &err, started.elapsed());
+        }
+        Err(panic) => {
+            crate::report::record_panic(config);
+            span.record("outcome", "panic");
+            let _entered = span.enter();
+            crate::log::err(format_args!("connection #{} panicked: {}", id, panic));
+        }
+    }
+    let open = limits.open.fetch_sub(1, Ordering::Relaxed) - 1;
+    crate::report::record_open(open, Some(started.elapsed()), config);
+}
+
+// Preamble, method negotiation, auth and the request itself, all the client has to send up front
+async fn socks5_negotiate<S: Read + Write + Unpin + Send>(
+    stream: &mut S,
+    ctx: &mut ConnContext,
+    config: &Config,
+) -> Result<(u8, Address), (Phase, Socks5Error)> {
+    if let Some(preamble) = &config.preamble {
+        socks5_preamble(stream, preamble)
+            .await
+            .map_err(|err| (Phase::Handshake, err))?;
+    }
+
+    let mut head = [0u8; 2];
+    stream
+        .read_exact(&mut head)
+        .await
+        .map_err(|err| (Phase::Handshake, err.into()))?;
+    if head == crate::http::CONNECT_HEAD && config.http_connect {
+        ctx.protocol = Protocol::Http;
+        let peer = ctx.peer.map(|peer| peer.ip());
+        let (target, user) = crate::http::request(stream, head, peer, config)
+            .await
+            .map_err(|err| match err {
+                Socks5Error::AuthFailed(_)
+                | Socks5Error::UserBound(_)
+                | Socks5Error::NoAcceptableMethod => (Phase::Auth, err),
+                _ => (Phase::Handshake, err),
+            })?;
+        ctx.user = user;
+        return Ok((CMD_CONNECT, target));
+    }
+    if head[0] == crate::socks4::SOCKS4_VERSION && config.socks4 {
+        ctx.protocol = Protocol::Socks4;
+        // SOCKS4 has no way to authenticate
+        if config.authenticator.wants_user_pass() {
+            return Err((Phase::Auth, Socks5Error::ConnectionNotAllowed));
+        }
+        return crate::socks4::request(stream, head)
+            .await
+            .map(|target| (CMD_CONNECT, target))
+            .map_err(|err| (Phase::Handshake, err));
+    }
+
+    let mut handshake = handshake::Server::new();
+    let method = socks5_handshake(stream, &mut handshake, head, config)
+        .await
+        .map_err(|err| (Phase::Handshake, err))?;
+    match method {
+        NO_AUTH => (),
+        USER_PASS => {
+            let peer = ctx.peer.map(|peer| peer.ip());
+            let (user, hops) = socks5_auth(stream, &mut handshake, peer, config)
+                .await
+                .map_err(|err| (Phase::Auth, err))?;
+            ctx.user = Some(user);
+            ctx.hops = hops;
         }
+        _ => {
+            let user = socks5_private_auth(stream, method, config)
+                .await
+                .map_err(|err| (Phase::Auth, err))?;
+            ctx.user = Some(user);
+        }
+    }
+
+    socks5_request(stream, &mut handshake)
+        .await
+        .map(|request| (request.cmd, request.target))
+        .map_err(|err| (Phase::Handshake, err))
+}
+
+// Everything up to an established outbound connection, recording what it learns in `ctx`
+async fn socks5_connect<S: Read + Write + Unpin + Send>(
+    stream: &mut S,
+    ctx: &mut ConnContext,
+    config: &Config,
+    self_addrs: &[SocketAddr],
+) -> Result<Outbound, (Phase, Socks5Error)> {
+    let negotiate = socks5_negotiate(stream, ctx, config);
+    let (cmd, target) = match config.handshake_timeout {
+        Some(handshake_timeout) => match async_std::future::timeout(handshake_timeout, negotiate)
+            .await
+        {
+            Ok(res) => res?,
+            Err(_) => {
+                let err = std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out");
+                return Err((Phase::Handshake, err.into()));
+            }
+        },
+        None => negotiate.await?,
+    };
+    ctx.target = Some(match &target {
+        Address::Ip(addr) => match crate::rdns::lookup(addr.ip()) {
+            Some(name) => format!("{} ({})", target, name),
+            None => target.to_string(),
+        },
+        Address::Domain(..) => target.to_string(),
+    });
+    crate::log::conn_request(ctx);
+
+    // The target of a UDP ASSOCIATE is only the client's own source hint, each datagram names its destination
+    if cmd == CMD_UDP_ASSOCIATE {
+        return crate::udp::bind(ctx.local)
+            .await
+            .map(Outbound::Udp)
+            .map_err(|err| (Phase::Connect, err.into()));
+    }
+
+    if cmd == CMD_BIND {
+        return socks5_bind(stream, &target, ctx, config)
+            .await
+            .map(|(remote, peer)| Outbound::Tcp(remote, Some(Address::from(peer))));
+    }
+
+    if cmd == CMD_RESOLVE || cmd == CMD_RESOLVE_PTR {
+        return socks5_lookup(cmd, &target, ctx.hops, config)
+            .await
+            .map(Outbound::Resolved)
+            .map_err(|err| (Phase::Resolve, err));
+    }
+
+    let addrs = match decide(&target, ctx, config, self_addrs).await {
+        Decision::Connect(addrs) => addrs,
+        Decision::Deny(deny) => return socks5_deny(deny, ctx, config).await,
+        Decision::Refuse(phase, err) => return Err((phase, err)),
+    };
+
+    crate::mirror::connect(ctx, &addrs, config);
+
+    // With --fast-reply the client can start sending while the connect is still in flight;
+    // the kernel buffers its bytes until the relay picks them up
+    if config.fast_reply {
+        reply_success(stream, ctx, None)
+            .await
+            .map_err(|err| (Phase::Reply, err.into()))?;
+        ctx.replied = true;
+    }
+
+    if config.upstream.is_some() {
+        // Whatever was checked here is what the upstream gets, so it can't resolve to something else
+        let target = match addrs.first() {
+            Some(addr) => Address::Ip(*addr),
+            None => target,
+        };
+        return crate::upstream::connect(&target, ctx.hops, config)
+            .await
+            .map(|(remote, bnd)| {
+                let bnd = connect_bnd(&remote, bnd, config);
+                Outbound::Tcp(remote, bnd)
+            })
+            .map_err(|err| (Phase::Connect, err));
+    }
+
+    crate::outbound::connect(addrs.as_slice(), config)
+        .await
+        .map(|remote| {
+            let bnd = connect_bnd(&remote, None, config);
+            Outbound::Tcp(remote, bnd)
+        })
+        .map_err(|err| (Phase::Connect, err.into()))
+}
+
+// What a CONNECT to `target` comes to, as the server acts on it and `check-config`
+// reports it
+pub(crate) enum Decision {
+    // The addresses to try, none when the name is left for the upstream to resolve
+    Connect(Vec<SocketAddr>),
+    // Turned away by the exit policy, with what its rule says to do instead
+    Deny(Option<DenyAction>),
+    Refuse(Phase, Socks5Error),
+}
+
+// Everything that decides whether a CONNECT may go ahead and where: the exit policy, the
+// domain lists, rebind protection, --deny-private and the proxy's own addresses. What it
+// resolves is recorded in `ctx`.
+pub(crate) async fn decide(
+    target: &Address,
+    ctx: &mut ConnContext,
+    config: &Config,
+    self_addrs: &[SocketAddr],
+) -> Decision {
+    // A domain the exit policy turns away whatever it resolves to isn't looked up at all
+    let decided = match (target, &config.exit_policy) {
+        (Address::Domain(..), Some(policy)) => policy.decide_port(target.port()),
+        _ => None,
+    };
+    if let Some((false, deny)) = decided {
+        return Decision::Deny(deny);
+    }
 
-        TYP_IPV6 => {
-            stream.read_exact(&mut buf[..16]).await?;
-            if let Ok(bs) = crate::ioutil::try_into_wrapper::<&[u8], [u8; 16]>(&buf[..16]) {
-                host = vec![IpAddr::V6(Ipv6Addr::from(bs))];
-            } else {
-                return Err(Socks5Error::ParseAddrError);
+    // When chaining, a domain goes to the upstream unresolved so its exit does the lookup,
+    // unless the exit policy, rebind protection or --deny-private need the addresses to decide
+    let unresolved = matches!(target, Address::Domain(..))
+        && match config.dns_mode {
+            DnsMode::Auto => {
+                config.upstream.is_some()
+                    && (config.exit_policy.is_none() || decided.is_some())
+                    && !config.rebind_protection
+                    && !config.deny_private
             }
+            DnsMode::Local => false,
+            DnsMode::Remote => true,
+        };
+    // With remote lookups a policy that needs the addresses has nothing to go on
+    if unresolved && config.exit_policy.is_some() && decided.is_none() {
+        return Decision::Refuse(Phase::Connect, Socks5Error::ConnectionNotAllowed);
+    }
+    let mut addrs = if unresolved {
+        if let Address::Domain(domain, _) = &target {
+            if !crate::domains::allowed(domain, config) {
+                return Decision::Refuse(Phase::Connect, Socks5Error::ConnectionNotAllowed);
+            }
+        }
+        vec![]
+    } else {
+        match socks5_resolve(target, config).await {
+            Ok(addrs) => addrs,
+            Err(err) => return Decision::Refuse(Phase::Resolve, err),
         }
-        _ => return Err(Socks5Error::UnrecognizedAddrType),
     };
+    ctx.resolved = addrs.iter().map(|addr| addr.ip()).collect();
 
-    stream.read_exact(&mut buf[..2]).await?;
+    // Left unresolved, the target was already accepted on its port alone
+    if let (Some(policy), false) = (&config.exit_policy, unresolved) {
+        let deny = addrs.first().and_then(|addr| policy.deny_action(addr));
+        addrs.retain(|addr| policy.allows(addr));
+        if addrs.is_empty() {
+            return Decision::Deny(deny);
+        }
+    }
+
+    if addrs.iter().any(|addr| self_addrs.contains(addr)) {
+        return Decision::Refuse(Phase::Connect, Socks5Error::ConnectionNotAllowed);
+    }
 
-    // Transmute [u8; _] to SocketAddr manually,
-    // to avoid `<str as async_std::net::ToSocketAddrs>::to_socket_addrs`'s shitty logic
-    Ok(host
-        .into_iter()
-        .map(|h| SocketAddr::new(h, unsafe { *(buf.as_ptr() as *const u16) }.to_be()))
-        .collect())
+    Decision::Connect(addrs)
 }
 
-async fn socks5_forward(
-    mut local: TcpStream,
-    target: Vec<SocketAddr>,
-) -> Result<(), std::io::Error> {
-    let mut remote = TcpStream::connect(target.as_slice()).await?;
-
-    match remote.peer_addr() {
-        Ok(SocketAddr::V4(ipv4)) => {
-            let buf = [
-                SOCKS_VERSION,
-                RESP_SUCCESS,
-                RSV,
-                TYP_IPV4,
-                0x0,
-                0x0,
-                0x0,
-                0x0,
-                0x0,
-                0x0,
-            ];
-
-            unsafe {
-                *(buf.as_ptr().offset(4) as *mut u32) = u32::from(*ipv4.ip()).to_be();
-                *(buf.as_ptr().offset(8) as *mut u16) = ipv4.port().to_be();
-            };
-            local.write_all(&buf).await?;
+// CONNECT's BND: RFC 1928 has it be the address the server connected from, which
+// --hide-bnd keeps to itself. Through --upstream that's the upstream's, as the local address
+// is only the first hop's.
+fn connect_bnd(remote: &TcpStream, upstream: Option<Address>, config: &Config) -> Option<Address> {
+    if config.hide_bnd {
+        return None;
+    }
+    upstream.or_else(|| remote.local_addr().ok().map(Address::from))
+}
+
+// RESOLVE answers with the first address of a name, RESOLVE_PTR with the name of an address
+async fn socks5_lookup(
+    cmd: u8,
+    target: &Address,
+    hops: u32,
+    config: &Config,
+) -> Result<Address, Socks5Error> {
+    if let Address::Ip(addr) = target {
+        if config.deny_private && crate::ioutil::is_internal(&addr.ip(), config.nat64_prefix) {
+            return Err(Socks5Error::ConnectionNotAllowed);
+        }
+    }
+    if let Address::Domain(domain, _) = target {
+        if cmd == CMD_RESOLVE_PTR {
+            return Err(Socks5Error::UnrecognizedAddrType);
         }
+        if !crate::domains::allowed(domain, config) {
+            return Err(Socks5Error::ConnectionNotAllowed);
+        }
+    }
+    if config.dns_mode == DnsMode::Remote {
+        return crate::upstream::lookup(cmd, target, hops, config).await;
+    }
 
-        Ok(SocketAddr::V6(ipv6)) => {
-            let mut buf = [0u8; 22];
-            buf[0] = SOCKS_VERSION;
-            buf[1] = RESP_SUCCESS;
-            buf[2] = RSV;
-            buf[3] = TYP_IPV6;
+    // A domain only gets this far with RESOLVE
+    match target {
+        Address::Domain(..) => {
+            let addrs = socks5_resolve(target, config).await?;
+            match addrs.first() {
+                Some(addr) => Ok(Address::from_ip(addr.ip(), 0)),
+                None => Err(std::io::Error::from(std::io::ErrorKind::NotFound).into()),
+            }
+        }
+        Address::Ip(addr) if cmd == CMD_RESOLVE => Ok(Address::from_ip(addr.ip(), 0)),
+        // Always a real PTR query: the reverse map only labels logs, as any client's
+        // lookups end up in it
+        Address::Ip(addr) => {
+            let name = crate::resolver::lookup_addr(addr.ip(), config).await?;
+            Ok(Address::Domain(name, 0))
+        }
+    }
+}
 
-            unsafe {
-                *(buf.as_ptr().offset(4) as *mut u128) = u128::from(*ipv6.ip()).to_be();
-                *(buf.as_ptr().offset(20) as *mut u16) = ipv6.port().to_be();
-            };
-            local.write_all(&buf).await?;
+// Carries out what the exit policy rule that denied the request asks for
+async fn socks5_deny(
+    deny: Option<DenyAction>,
+    ctx: &mut ConnContext,
+    config: &Config,
+) -> Result<Outbound, (Phase, Socks5Error)> {
+    match deny {
+        Some(DenyAction::Redirect(notice)) => {
+            return crate::outbound::connect(&[notice], config)
+                .await
+                .map(|remote| {
+                    let bnd = connect_bnd(&remote, None, config);
+                    Outbound::Tcp(remote, bnd)
+                })
+                .map_err(|err| (Phase::Connect, err.into()));
+        }
+        Some(DenyAction::Respond(mode)) => ctx.error_mode = Some(mode),
+        None => (),
+    }
+    Err((Phase::Connect, Socks5Error::ConnectionNotAllowed))
+}
+
+// Waits for the one inbound connection a BIND asks for, announcing the listening address
+// in the first reply; the second reply goes out like a CONNECT's
+async fn socks5_bind<S: Read + Write + Unpin>(
+    stream: &mut S,
+    target: &Address,
+    ctx: &ConnContext,
+    config: &Config,
+) -> Result<(TcpStream, SocketAddr), (Phase, Socks5Error)> {
+    let listener = TcpListener::bind(SocketAddr::new(crate::ioutil::listen_ip(ctx.local), 0))
+        .await
+        .map_err(|err| (Phase::Connect, err.into()))?;
+    let bnd = listener
+        .local_addr()
+        .ok()
+        .map(|addr| Address::from(external_bnd(addr, ctx, config)));
+    socks5_reply(stream, RESP_SUCCESS, bnd)
+        .await
+        .map_err(|err| (Phase::Reply, err.into()))?;
+
+    // The client isn't supposed to send anything until the second reply, so any read ends the wait
+    let accept = listener.accept();
+    let mut buf = [0u8; 1];
+    let closed = stream.read(&mut buf);
+    futures::pin_mut!(accept, closed);
+    let (remote, peer) = match future::select(accept, closed).await {
+        Either::Left((res, _)) => res.map_err(|err| (Phase::Connect, err.into()))?,
+        Either::Right(_) => {
+            return Err((
+                Phase::Connect,
+                std::io::Error::new(
+                    std::io::ErrorKind::ConnectionAborted,
+                    "client left before the inbound connection arrived",
+                )
+                .into(),
+            ))
         }
-        _ => (),
     };
 
-    let mut local_clone = local.clone();
-    let mut remote_clone = remote.clone();
+    let expected = match target {
+        Address::Ip(addr) if !addr.ip().is_unspecified() => addr.ip() == peer.ip(),
+        _ => true,
+    };
+    let allowed = match &config.exit_policy {
+        Some(policy) => policy.allows(&peer),
+        None => true,
+    };
+    if !expected || !allowed {
+        return Err((Phase::Connect, Socks5Error::ConnectionNotAllowed));
+    }
 
-    task::spawn(async move {
-        let _ = io::copy(&mut remote_clone, &mut local_clone).await;
-        let _ = local_clone.shutdown(Shutdown::Both);
-        let _ = remote_clone.shutdown(Shutdown::Both);
-    });
+    Ok((remote, peer))
+}
 
-    io::copy(&mut local, &mut remote).await?;
-    local.shutdown(Shutdown::Both)?;
-    remote.shutdown(Shutdown::Both)?;
+// What BIND and UDP ASSOCIATE have the client reach: the socket's own address, unless
+// --external-addr names the public one for the address the client came in on
+fn external_bnd(bound: SocketAddr, ctx: &ConnContext, config: &Config) -> SocketAddr {
+    let local = ctx.local.map(|local| local.ip());
+    let external = config
+        .external_addrs
+        .iter()
+        .find(|(on, _)| on.is_some() && *on == local)
+        .or_else(|| config.external_addrs.iter().find(|(on, _)| on.is_none()));
+    match external {
+        Some((_, ip)) => SocketAddr::new(*ip, bound.port()),
+        None => bound,
+    }
+}
 
-    Ok(())
+// Turns a failed connection away the way `--on-error`, or the rule that denied it, asks for
+async fn socks5_reject<S: Stream>(
+    stream: &mut Traced<S>,
+    phase: Phase,
+    err: &Socks5Error,
+    mode: ErrorMode,
+    protocol: Protocol,
+) {
+    match mode {
+        ErrorMode::Reply => match (reply_code(phase, err), protocol) {
+            (Some(rep), Protocol::Socks5) => {
+                let _ = socks5_reply_failure(stream, rep).await;
+            }
+            // SOCKS4 has a single code for every failure
+            (Some(_), Protocol::Socks4) => {
+                let _ = crate::socks4::reply(stream, crate::socks4::RESP_REJECTED, None).await;
+            }
+            (Some(rep), Protocol::Http) => {
+                let _ = crate::http::reply_failure(stream, rep).await;
+            }
+            (None, _) => (),
+        },
+        ErrorMode::Close => (),
+        #[cfg(unix)]
+        ErrorMode::Reset => {
+            let _ = crate::ioutil::set_linger_zero(stream.get_ref().as_raw_fd());
+        }
+    }
 }
 
-pub async fn start_socks5_server(
-    addr: &String,
-    max_connections: usize,
+// None when the client can't be spoken to in SOCKS5 at all, or has already gone away
+fn reply_code(phase: Phase, err: &Socks5Error) -> Option<u8> {
+    match (phase, err) {
+        (_, Socks5Error::UnsupportedCommand) => Some(RESP_CMD_NOT_SUPPORTED),
+        (_, Socks5Error::UnrecognizedAddrType) => Some(RESP_ADDR_NOT_SUPPORTED),
+        (_, Socks5Error::ConnectionNotAllowed) => Some(RESP_NOT_ALLOWED),
+        (Phase::Handshake, _) | (Phase::Auth, _) => None,
+        (Phase::Resolve, _) => Some(RESP_HOST_UNREACHABLE),
+        (_, Socks5Error::IOError(err)) => Some(match err.kind() {
+            std::io::ErrorKind::ConnectionRefused => RESP_CONNECTION_REFUSED,
+            std::io::ErrorKind::TimedOut => RESP_TTL_EXPIRED,
+            std::io::ErrorKind::HostUnreachable => RESP_HOST_UNREACHABLE,
+            std::io::ErrorKind::NetworkUnreachable => RESP_NETWORK_UNREACHABLE,
+            #[cfg(unix)]
+            _ => match err.raw_os_error() {
+                Some(libc::ENETUNREACH) | Some(libc::ENETDOWN) => RESP_NETWORK_UNREACHABLE,
+                Some(libc::EHOSTUNREACH) | Some(libc::EHOSTDOWN) => RESP_HOST_UNREACHABLE,
+                // An IPv6 target on a host without IPv6, or the other way round
+                Some(libc::EAFNOSUPPORT) => RESP_ADDR_NOT_SUPPORTED,
+                _ => RESP_GENERAL_FAILURE,
+            },
+            #[cfg(not(unix))]
+            _ => RESP_GENERAL_FAILURE,
+        }),
+        _ => Some(RESP_GENERAL_FAILURE),
+    }
+}
+
+async fn serve_connection<S: Stream>(
+    stream: S,
+    mut ctx: ConnContext,
+    handshake: Option<LimitPermit<'_>>,
+    config: &Config,
+    limits: &Limits,
+    self_addrs: &[SocketAddr],
+) -> Result<ConnContext, ConnError> {
+    let mut stream = Traced::new(stream, ctx.id, config.trace);
+    let registration = if config.tunnel_stats || config.snapshot.is_some() {
+        Some(config.state.tunnels.register(ctx.id, &ctx.client))
+    } else {
+        None
+    };
+
+    let connected = socks5_connect(&mut stream, &mut ctx, config, self_addrs).await;
+    drop(handshake);
+    // Over the limit, the outbound is dropped and the client told like any failed connect
+    let (connected, _tunnel) = match (connected, config.max_tunnels) {
+        (Ok(outbound @ Outbound::Tcp(..)), Some(max))
+        | (Ok(outbound @ Outbound::Udp(_)), Some(max)) => match limits.tunnels.try_acquire(max) {
+            Some(permit) => (Ok(outbound), Some(permit)),
+            None => {
+                let err = std::io::Error::other("tunnel limit reached").into();
+                (Err((Phase::Connect, err)), None)
+            }
+        },
+        (connected, _) => (connected, None),
+    };
+    let remote = match connected {
+        Ok(remote) => remote,
+        Err((phase, err)) => {
+            match (&err, &config.decoy) {
+                (Socks5Error::BadPreamble(read), Some(decoy))
+                | (Socks5Error::NotSocks(read), Some(decoy)) => {
+                    let _ = crate::decoy::serve(stream.into_inner(), read, decoy, config).await;
+                }
+                // Too late for a failure reply, so a reset is all that tells the client
+                #[cfg(unix)]
+                _ if ctx.replied => {
+                    let _ = crate::ioutil::set_linger_zero(stream.get_ref().as_raw_fd());
+                }
+                _ => {
+                    let mode = ctx.error_mode.unwrap_or(config.on_error);
+                    socks5_reject(&mut stream, phase, &err, mode, ctx.protocol).await
+                }
+            }
+            return Err(ctx.fail(phase, err));
+        }
+    };
+    if let Outbound::Resolved(answer) = &remote {
+        if let Err(err) = socks5_reply(&mut stream, RESP_SUCCESS, Some(answer.clone())).await {
+            return Err(ctx.fail(Phase::Reply, err));
+        }
+        return Ok(ctx);
+    }
+    let bnd_addr = match &remote {
+        Outbound::Tcp(remote, bnd) => {
+            if let Ok(peer) = remote.peer_addr() {
+                ctx.resolved = vec![peer.ip()];
+            }
+            bnd.clone()
+        }
+        Outbound::Udp(socket) => socket
+            .local_addr()
+            .ok()
+            .map(|addr| Address::from(external_bnd(addr, &ctx, config))),
+        // Answered above, there's no tunnel to set up
+        Outbound::Resolved(_) => unreachable!(),
+    };
+    if !ctx.replied {
+        if let Err(err) = reply_success(&mut stream, &ctx, bnd_addr).await {
+            return Err(ctx.fail(Phase::Reply, err));
+        }
+    }
+
+    // Only TCP tunnels have their bytes counted, anything else leaves the listing here
+    let registration = match (&remote, registration) {
+        (Outbound::Tcp(..), Some(registration)) => {
+            let target = ctx.target.as_deref().unwrap_or("?");
+            registration.establish(ctx.user.as_deref(), target);
+            Some(registration)
+        }
+        _ => None,
+    };
+    let relay = match remote {
+        Outbound::Tcp(remote, _) => {
+            let stats = registration.as_ref().map(|r| &*r.stats);
+            Either::Left(socks5_relay(stream.into_inner(), remote, stats, config))
+        }
+        Outbound::Udp(socket) => {
+            let peer = ctx.peer.map(|addr| addr.ip());
+            Either::Right(crate::udp::relay(stream.into_inner(), socket, peer, config))
+        }
+        Outbound::Resolved(_) => unreachable!(),
+    };
+    let res = match config.max_session {
+        // Dropping the relay on expiry closes both sockets
+        Some(max_session) => match async_std::future::timeout(max_session, relay).await {
+            Ok(res) => res,
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("session limit of {}s reached", max_session.as_secs()),
+            )),
+        },
+        None => relay.await,
+    };
+
+    let (sent, received) = match res {
+        Ok(bytes) => bytes,
+        Err(err) => return Err(ctx.fail(Phase::Relay, err)),
+    };
+    crate::report::record_bytes(sent, received, config);
+    ctx.sent = sent;
+    ctx.received = received;
+    Ok(ctx)
+}
+
+pub(crate) async fn serve_tcp(
+    listener: TcpListener,
+    config: &Config,
+    limits: &Limits,
+    self_addrs: &[SocketAddr],
+    stop: Stop,
 ) -> Result<(), std::io::Error> {
-    TcpListener::bind(addr)
-        .await?
-        .incoming()
-        .for_each_concurrent(max_connections, |stream| async move {
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(path) = &config.listen_filter {
+            use std::os::unix::io::AsRawFd;
+            crate::filter::attach(listener.as_raw_fd(), &mut crate::filter::load(path)?)?;
+        }
+    }
+
+    let prioritized = !config.priority_nets.is_empty();
+    let per_ip = &limits.per_ip;
+
+    accept_gated(listener.incoming().take_until(stop), limits, !prioritized)
+        .for_each_concurrent(None, |(stream, slot)| async move {
+            let _slot = slot;
             if let Ok(stream) = stream {
-                match socks5_handshake(&stream).await {
-                    Ok(target) => {
-                        let _ = socks5_forward(stream, target).await;
-                    }
-                    Err(_) => (),
+                let peer = stream.peer_addr().ok();
+                let priority = peer.is_some_and(|peer| {
+                    config
+                        .priority_nets
+                        .iter()
+                        .any(|net| crate::policy::cidr_contains(*net, peer.ip()))
+                });
+                if !crate::shed::admit(config, priority) {
+                    return;
+                }
+                let _ip_permit = match (per_ip, peer) {
+                    (Some(per_ip), Some(peer)) => match per_ip.try_acquire(peer.ip()) {
+                        Some(permit) => Some(permit),
+                        None => {
+                            crate::report::record_rejection(config);
+                            return;
+                        }
+                    },
+                    _ => None,
+                };
+                let _permit = match &limits.connections {
+                    Some(gate) if prioritized => Some(gate.acquire(priority).await),
+                    _ => None,
+                };
+                let client = match peer {
+                    Some(addr) => match config.geoip.as_ref().and_then(|db| db.lookup(addr.ip())) {
+                        Some(geo) => format!("{} ({})", addr, geo),
+                        None => addr.to_string(),
+                    },
+                    None => "?".to_string(),
                 };
+                let mut ctx = ConnContext::new(crate::ioutil::next_conn_id(), client);
+                ctx.local = stream.local_addr().ok();
+                ctx.peer = peer;
+                handle_connection(stream, ctx, config, limits, self_addrs).await;
             };
         })
         .await;
 
     Ok(())
 }
+
+// Pairs each connection with its --max-connections slot. Taken `before` accepting, a full
+// server leaves clients waiting in the listen backlog; otherwise the caller takes the slot
+// once it has accepted, so priority networks can be let in first.
+fn accept_gated<'a, S, T>(
+    incoming: S,
+    limits: &'a Limits,
+    before: bool,
+) -> impl futures::Stream<Item = (T, Option<Permit<'a>>)> + 'a
+where
+    S: futures::Stream<Item = T> + Unpin + 'a,
+{
+    futures::stream::unfold(incoming, move |mut incoming| async move {
+        loop {
+            // Whoever connects while paused waits in the listen backlog. An accept already
+            // waiting gives up on a pause, which leaves the listener untouched.
+            limits.pause.until(false).await;
+            let slot = match &limits.connections {
+                Some(gate) if before => Some(gate.acquire(false).await),
+                _ => None,
+            };
+            let paused = limits.pause.until(true);
+            futures::pin_mut!(paused);
+            // The pause is polled first, so it wins over a connection already waiting
+            if let Either::Right((stream, _)) = future::select(paused, incoming.next()).await {
+                return Some(((stream?, slot), incoming));
+            }
+        }
+    })
+}
+
+// Resolves once the server should stop accepting; connections already in flight are left to finish
+pub(crate) type Stop = Shared<BoxFuture<'static, ()>>;
+
+// Serves on `listener` when the caller bound one itself, otherwise on `config.bind_addr`
+pub(crate) async fn start_socks5_server(
+    config: Config,
+    listener: Option<std::net::TcpListener>,
+    bound: Option<oneshot::Sender<SocketAddr>>,
+    pause: Arc<Pause>,
+    stop: Stop,
+) -> Result<(), std::io::Error> {
+    let config = Arc::new(config);
+
+    if let Some(base) = config.test_targets {
+        crate::targets::spawn(base).await?;
+    }
+    let limits = Arc::new(Limits::new(&config, pause));
+
+    let listener = match listener {
+        Some(listener) => {
+            listener.set_nonblocking(true)?;
+            Some(TcpListener::from(listener))
+        }
+        None => None,
+    };
+
+    // With --acceptors every listener is served on a thread of its own, and this one only
+    // waits for them. A listener the caller bound is served here alone.
+    #[cfg(target_os = "linux")]
+    let (listener, acceptors) = match listener {
+        Some(listener) => (Either::Left(listener), vec![]),
+        None if config.acceptors > 1 => {
+            let (addr, acceptors) = crate::acceptor::spawn(&config, &limits, &stop)?;
+            (Either::Right(addr), acceptors)
+        }
+        None => (
+            Either::Left(TcpListener::bind(&config.bind_addr).await?),
+            vec![],
+        ),
+    };
+    #[cfg(not(target_os = "linux"))]
+    let listener: Either<_, SocketAddr> = match listener {
+        Some(listener) => Either::Left(listener),
+        None => Either::Left(TcpListener::bind(&config.bind_addr).await?),
+    };
+
+    let local_addr = match &listener {
+        Either::Left(listener) => listener.local_addr()?,
+        Either::Right(addr) => *addr,
+    };
+    if let Some(bound) = bound {
+        let _ = bound.send(local_addr);
+    }
+    let self_addrs = &self_addrs(local_addr, &config);
+    let config = &*config;
+    let limits = &*limits;
+    // These live as long as the listeners do
+    let reload = crate::reload::watch(config, stop.clone());
+    let schedule = config
+        .state
+        .schedule
+        .run(&config.rate_windows, stop.clone());
+    let tunnels = crate::tunnels::run(
+        &config.state.tunnels,
+        config.snapshot.clone(),
+        config.tunnel_stats,
+        stop.clone(),
+    );
+    let tcp_stop = stop.clone();
+    let tcp = async move {
+        let res = match listener {
+            Either::Left(listener) => {
+                serve_tcp(listener, config, limits, self_addrs, tcp_stop).await
+            }
+            Either::Right(_) => Ok(()),
+        };
+        #[cfg(target_os = "linux")]
+        let res = blocking::unblock(move || {
+            acceptors
+                .into_iter()
+                .map(|acceptor| acceptor.join().unwrap_or(Ok(())))
+                .fold(res, Result::and)
+        })
+        .await;
+        res
+    };
+
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(name) = &config.unix_listen {
+            let unix_listener = crate::unix::bind(name)?;
+            let unix = accept_gated(unix_listener.incoming().take_until(stop), limits, true)
+                .for_each_concurrent(None, |(stream, slot)| async move {
+                    let _slot = slot;
+                    if let Ok(stream) = stream {
+                        // Only peers that passed a credential check are trusted
+                        let checked =
+                            !config.allow_uids.is_empty() || !config.allow_gids.is_empty();
+                        if crate::unix::peer_allowed(&stream, config)
+                            && crate::shed::admit(config, checked)
+                        {
+                            let client = crate::unix::peer_name(&stream);
+                            let ctx = ConnContext::new(crate::ioutil::next_conn_id(), client);
+                            handle_connection(stream, ctx, config, limits, self_addrs).await;
+                        }
+                    };
+                });
+
+            return futures::join!(schedule, tcp, unix, reload, tunnels).1;
+        }
+    }
+
+    futures::join!(schedule, tcp, reload, tunnels).1
+}
@@ -0,0 +1,105 @@
+use crate::config::Config;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Rates are averaged over this many one-second buckets
+const WINDOW_SECS: u64 = 10;
+
+// A server's accept and handshake failure rates, and whether it's shedding because of them
+#[derive(Default)]
+pub(crate) struct Shed {
+    accepts: Window,
+    failures: Window,
+    shedding: AtomicBool,
+}
+
+struct Window {
+    // (second, count) per bucket, indexed by second modulo the window
+    buckets: Mutex<[(u64, u64); WINDOW_SECS as usize]>,
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Window {
+            buckets: Mutex::new([(0, 0); WINDOW_SECS as usize]),
+        }
+    }
+}
+
+impl Window {
+    fn record(&self, now: u64) {
+        let mut buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+        let bucket = &mut buckets[(now % WINDOW_SECS) as usize];
+        if bucket.0 != now {
+            *bucket = (now, 0);
+        }
+        bucket.1 += 1;
+    }
+
+    fn per_sec(&self, now: u64) -> u64 {
+        let buckets = self.buckets.lock().unwrap_or_else(|err| err.into_inner());
+        let total: u64 = buckets
+            .iter()
+            .filter(|(sec, _)| now.saturating_sub(*sec) < WINDOW_SECS)
+            .map(|(_, count)| count)
+            .sum();
+        total / WINDOW_SECS
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Counts an accepted connection and says whether it may be served. Once accepts or
+// handshake failures per second exceed their thresholds, every new connection is
+// dropped until the rates fall back under them, except from `trusted` sources: the
+// --priority-net networks and Unix peers whose credentials were checked.
+pub(crate) fn admit(config: &Config, trusted: bool) -> bool {
+    if config.shed_accept_rate.is_none() && config.shed_failure_rate.is_none() {
+        return true;
+    }
+
+    let shed = &config.state.shed;
+    let now = now_secs();
+    shed.accepts.record(now);
+
+    let accepts = shed.accepts.per_sec(now);
+    let failures = shed.failures.per_sec(now);
+    let shedding = config.shed_accept_rate.is_some_and(|max| accepts > max)
+        || config.shed_failure_rate.is_some_and(|max| failures > max);
+
+    if shed.shedding.swap(shedding, Ordering::Relaxed) != shedding {
+        crate::report::record_shedding(shedding);
+        crate::log::warn(format_args!(
+            "{} load shedding at {} accepts/s, {} handshake failures/s",
+            if shedding { "entering" } else { "leaving" },
+            accepts,
+            failures
+        ));
+    }
+
+    if shedding && !trusted {
+        crate::report::record_shed(config);
+        return false;
+    }
+    true
+}
+
+pub(crate) fn record_failure(config: &Config) {
+    if config.shed_failure_rate.is_some() {
+        config.state.shed.failures.record(now_secs());
+    }
+}
+
+// A server that stops while shedding no longer counts as shedding in the report
+impl Drop for Shed {
+    fn drop(&mut self) {
+        if *self.shedding.get_mut() {
+            crate::report::record_shedding(false);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use crate::address::Address;
+use crate::errors::Socks5Error;
+use async_std::{
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    prelude::*,
+};
+
+pub(crate) const SOCKS4_VERSION: u8 = 0x4;
+const CMD_CONNECT: u8 = 0x1;
+const REPLY_VERSION: u8 = 0x0;
+pub(crate) const RESP_GRANTED: u8 = 90;
+pub(crate) const RESP_REJECTED: u8 = 91;
+// Longest user id or SOCKS4a domain read before giving up on the terminating NUL
+const MAX_FIELD: usize = 0xff;
+
+// The rest of a SOCKS4/4a request, after the version and command in `head`.
+// Only CONNECT is supported; the user id is read and ignored since SOCKS4 can't prove it.
+pub(crate) async fn request<S: Read + Unpin>(
+    stream: &mut S,
+    head: [u8; 2],
+) -> Result<Address, Socks5Error> {
+    let mut buf = [0u8; 6];
+    stream.read_exact(&mut buf).await?;
+    let port = u16::from_be_bytes([buf[0], buf[1]]);
+    let ip = Ipv4Addr::new(buf[2], buf[3], buf[4], buf[5]);
+
+    read_field(stream).await?;
+    // SOCKS4a: 0.0.0.x, x non-zero, means a domain follows the user id
+    let target = match ip.octets() {
+        [0, 0, 0, x] if x != 0 => {
+            let domain = read_field(stream).await?;
+            let domain = String::from_utf8(domain).map_err(|_| Socks5Error::ParseAddrError)?;
+            Address::Domain(domain, port)
+        }
+        _ => Address::from_ip(IpAddr::V4(ip), port),
+    };
+
+    if head[1] != CMD_CONNECT {
+        return Err(Socks5Error::UnsupportedCommand);
+    }
+    Ok(target)
+}
+
+async fn read_field<S: Read + Unpin>(stream: &mut S) -> Result<Vec<u8>, Socks5Error> {
+    let mut field = vec![];
+    let mut byte = [0u8; 1];
+    loop {
+        stream.read_exact(&mut byte).await?;
+        if byte[0] == 0 {
+            return Ok(field);
+        }
+        if field.len() == MAX_FIELD {
+            return Err(Socks5Error::ParseAddrError);
+        }
+        field.push(byte[0]);
+    }
+}
+
+// SOCKS4 replies only have room for an IPv4 address; anything else goes out as zeros
+pub(crate) async fn reply<S: Write + Unpin>(
+    stream: &mut S,
+    rep: u8,
+    bnd_addr: Option<SocketAddr>,
+) -> Result<(), std::io::Error> {
+    let mut buf = [REPLY_VERSION, rep, 0, 0, 0, 0, 0, 0];
+    if let Some(SocketAddr::V4(addr)) = bnd_addr {
+        buf[2..4].copy_from_slice(&addr.port().to_be_bytes());
+        buf[4..].copy_from_slice(&addr.ip().octets());
+    }
+    stream.write_all(&buf).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_std::task;
+
+    fn parse(cmd: u8, rest: &[u8]) -> (Result<Address, Socks5Error>, Vec<u8>) {
+        task::block_on(async {
+            let mut stream = rest;
+            let res = request(&mut stream, [SOCKS4_VERSION, cmd]).await;
+            (res, stream.to_vec())
+        })
+    }
+
+    #[test]
+    fn connect_to_ip() {
+        let (res, left) = parse(CMD_CONNECT, b"\x00\x50\xc0\x00\x02\x01bob\x00GET");
+        assert_eq!(res.unwrap(), Address::Ip("192.0.2.1:80".parse().unwrap()));
+        // Nothing past the user id's NUL is taken
+        assert_eq!(left, b"GET");
+
+        let (res, _) = parse(CMD_CONNECT, b"\x01\xbb\x00\x00\x00\x00\x00");
+        assert_eq!(res.unwrap(), Address::Ip("0.0.0.0:443".parse().unwrap()));
+    }
+
+    #[test]
+    fn socks4a_domain() {
+        let (res, left) = parse(
+            CMD_CONNECT,
+            b"\x01\xbb\x00\x00\x00\x07alice\x00example.com\x00",
+        );
+        assert_eq!(
+            res.unwrap(),
+            Address::Domain("example.com".to_string(), 443)
+        );
+        assert!(left.is_empty());
+
+        let (res, _) = parse(CMD_CONNECT, b"\x01\xbb\x00\x00\x00\x01\x00\xff\xfe\x00");
+        assert!(matches!(res, Err(Socks5Error::ParseAddrError)));
+    }
+
+    #[test]
+    fn fields_need_their_nul() {
+        let (res, _) = parse(CMD_CONNECT, b"\x00\x50\xc0\x00\x02\x01bob");
+        assert!(matches!(res, Err(Socks5Error::IOError(_))));
+        let (res, _) = parse(CMD_CONNECT, b"\x01\xbb\x00\x00\x00\x01\x00example.com");
+        assert!(matches!(res, Err(Socks5Error::IOError(_))));
+
+        let mut long = b"\x00\x50\xc0\x00\x02\x01".to_vec();
+        long.extend(vec![b'a'; MAX_FIELD + 1]);
+        long.push(0);
+        let (res, _) = parse(CMD_CONNECT, &long);
+        assert!(matches!(res, Err(Socks5Error::ParseAddrError)));
+    }
+
+    #[test]
+    fn only_connect() {
+        let (res, left) = parse(0x2, b"\x00\x50\xc0\x00\x02\x01bob\x00");
+        assert!(matches!(res, Err(Socks5Error::UnsupportedCommand)));
+        assert!(left.is_empty());
+    }
+
+    #[test]
+    fn replies() {
+        task::block_on(async {
+            let mut buf = vec![];
+            reply(
+                &mut buf,
+                RESP_GRANTED,
+                Some("192.0.2.1:80".parse().unwrap()),
+            )
+            .await
+            .unwrap();
+            assert_eq!(buf, [0, 90, 0, 80, 192, 0, 2, 1]);
+
+            let mut buf = vec![];
+            reply(&mut buf, RESP_REJECTED, Some("[::1]:80".parse().unwrap()))
+                .await
+                .unwrap();
+            assert_eq!(buf, [0, 91, 0, 0, 0, 0, 0, 0]);
+        });
+    }
+}
@@ -0,0 +1,135 @@
+use async_io::Async;
+use async_std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+use std::time::Duration;
+
+const CHUNK: usize = 1 << 16;
+
+static PROBE: Once = Once::new();
+static SUPPORTED: AtomicBool = AtomicBool::new(false);
+
+// An owned descriptor, so the relay can register its own dup of each socket with the
+// reactor without touching the registration the async-std stream already holds
+struct Fd(RawFd);
+
+impl Fd {
+    fn dup(fd: RawFd) -> io::Result<Fd> {
+        match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) } {
+            -1 => Err(io::Error::last_os_error()),
+            fd => Ok(Fd(fd)),
+        }
+    }
+}
+
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+fn pipe() -> io::Result<(Fd, Fd)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((Fd(fds[0]), Fd(fds[1])))
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+// Whether the kernel can splice from a socket into a pipe, checked once on a socketpair
+pub fn supported() -> bool {
+    PROBE.call_once(|| {
+        let probe = || -> io::Result<()> {
+            let mut fds = [0; 2];
+            if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_STREAM, 0, fds.as_mut_ptr()) }
+                != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            let (a, b) = (Fd(fds[0]), Fd(fds[1]));
+            let (_pipe_r, pipe_w) = pipe()?;
+
+            if unsafe { libc::write(a.0, b"x".as_ptr() as *const libc::c_void, 1) } != 1 {
+                return Err(io::Error::last_os_error());
+            }
+            splice(b.0, pipe_w.0, 1).map(|_| ())
+        };
+        SUPPORTED.store(probe().is_ok(), Ordering::Relaxed);
+    });
+    SUPPORTED.load(Ordering::Relaxed)
+}
+
+// `ioutil::copy_bidirectional` through a pipe per direction, so the payload never
+// leaves the kernel
+pub(crate) async fn copy_bidirectional(
+    a: RawFd,
+    b: RawFd,
+    stall_timeout: Option<Duration>,
+) -> io::Result<(u64, u64)> {
+    let a = Async::new(Fd::dup(a)?)?;
+    let b = Async::new(Fd::dup(b)?)?;
+
+    futures::try_join!(
+        copy_one_way(&a, &b, stall_timeout),
+        copy_one_way(&b, &a, stall_timeout)
+    )
+}
+
+async fn copy_one_way(
+    src: &Async<Fd>,
+    dst: &Async<Fd>,
+    stall_timeout: Option<Duration>,
+) -> io::Result<u64> {
+    let (pipe_r, pipe_w) = pipe()?;
+    let mut total = 0;
+
+    loop {
+        let n = src.read_with(|src| splice(src.0, pipe_w.0, CHUNK)).await?;
+        if n == 0 {
+            unsafe { libc::shutdown(dst.get_ref().0, libc::SHUT_WR) };
+            return Ok(total);
+        }
+
+        // The pipe is drained before the next read, so only the socket can block here
+        let mut pending = n;
+        while pending > 0 {
+            let write = dst.write_with(|dst| splice(pipe_r.0, dst.0, pending));
+            pending -= match stall_timeout {
+                Some(stall_timeout) => io::timeout(stall_timeout, write).await.map_err(|err| {
+                    if err.kind() == io::ErrorKind::TimedOut {
+                        io::Error::new(io::ErrorKind::TimedOut, "write stalled")
+                    } else {
+                        err
+                    }
+                })?,
+                None => write.await?,
+            };
+        }
+        total += n as u64;
+    }
+}
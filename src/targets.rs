@@ -0,0 +1,59 @@
+use async_std::{
+    io,
+    net::{SocketAddr, TcpListener, TcpStream},
+    prelude::*,
+    task,
+};
+use futures::stream::StreamExt;
+
+// Echo (RFC 862), discard (RFC 863) and chargen (RFC 864) on three consecutive ports,
+// giving benchmarks and tests a deterministic destination that needs no network
+pub(crate) async fn spawn(base: SocketAddr) -> io::Result<()> {
+    let echo = TcpListener::bind(base).await?;
+    let discard = TcpListener::bind(SocketAddr::new(base.ip(), base.port() + 1)).await?;
+    let chargen = TcpListener::bind(SocketAddr::new(base.ip(), base.port() + 2)).await?;
+
+    task::spawn(serve(echo, |stream| async move {
+        let _ = io::copy(&mut &stream, &mut &stream).await;
+    }));
+    task::spawn(serve(discard, |stream| async move {
+        let _ = io::copy(&mut &stream, &mut io::sink()).await;
+    }));
+    task::spawn(serve(chargen, chargen_loop));
+
+    Ok(())
+}
+
+async fn serve<F, Fut>(listener: TcpListener, handler: F)
+where
+    F: Fn(TcpStream) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    listener
+        .incoming()
+        .for_each(|stream| {
+            if let Ok(stream) = stream {
+                task::spawn(handler(stream));
+            }
+            async {}
+        })
+        .await;
+}
+
+async fn chargen_loop(mut stream: TcpStream) {
+    const LINE_LEN: usize = 72;
+    let printable = (b' '..=b'~').collect::<Vec<_>>();
+
+    let mut line = [0u8; LINE_LEN + 2];
+    line[LINE_LEN] = b'\r';
+    line[LINE_LEN + 1] = b'\n';
+
+    for start in (0..printable.len()).cycle() {
+        for (i, b) in line[..LINE_LEN].iter_mut().enumerate() {
+            *b = printable[(start + i) % printable.len()];
+        }
+        if stream.write_all(&line).await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use crate::server::Stop;
+use async_std::task;
+use futures::future::{self, Either};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// How often throughput is sampled, and the window the average mostly reflects
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+const WINDOW_SECS: f64 = 5.0;
+
+// The servers running in this process, each with where SIGUSR2 writes its snapshot
+// (`--snapshot`), for the signals to go through; a server that's gone is dropped from it
+static SERVERS: Mutex<Vec<(Weak<Tunnels>, Option<String>)>> = Mutex::new(vec![]);
+
+// A server's live connections, by ID
+#[derive(Default)]
+pub(crate) struct Tunnels {
+    live: Mutex<BTreeMap<u64, Arc<TunnelStats>>>,
+}
+
+// A live connection as `--tunnel-stats` and `--snapshot` track it, from the handshake on
+pub(crate) struct TunnelStats {
+    client: String,
+    // Set once the handshake is done and the tunnel is relaying
+    established: Mutex<Option<Established>>,
+    started: Instant,
+    pub(crate) up: AtomicU64,
+    pub(crate) down: AtomicU64,
+    rates: Mutex<Rates>,
+}
+
+struct Established {
+    user: Option<String>,
+    target: String,
+}
+
+// Bytes per second each way, averaged over roughly the last WINDOW_SECS
+#[derive(Default)]
+struct Rates {
+    last_up: u64,
+    last_down: u64,
+    up: f64,
+    down: f64,
+}
+
+// Drops the tunnel from the listing once it's done
+pub(crate) struct Registration<'a> {
+    id: u64,
+    tunnels: &'a Tunnels,
+    pub(crate) stats: Arc<TunnelStats>,
+}
+
+impl Tunnels {
+    pub(crate) fn register(&self, id: u64, client: &str) -> Registration<'_> {
+        let stats = Arc::new(TunnelStats {
+            client: client.to_string(),
+            established: Mutex::new(None),
+            started: Instant::now(),
+            up: AtomicU64::new(0),
+            down: AtomicU64::new(0),
+            rates: Mutex::new(Rates::default()),
+        });
+        self.live
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .insert(id, stats.clone());
+        Registration {
+            id,
+            tunnels: self,
+            stats,
+        }
+    }
+}
+
+impl Registration<'_> {
+    pub(crate) fn establish(&self, user: Option<&str>, target: &str) {
+        *self
+            .stats
+            .established
+            .lock()
+            .unwrap_or_else(|err| err.into_inner()) = Some(Established {
+            user: user.map(str::to_string),
+            target: target.to_string(),
+        });
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.tunnels
+            .live
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .remove(&self.id);
+    }
+}
+
+// Lists the server's tunnels for the signals and, with `sample`, keeps folding each one's
+// bytes since the last sample into its moving average until the server stops
+pub(crate) async fn run(
+    tunnels: &Arc<Tunnels>,
+    snapshot: Option<String>,
+    sample: bool,
+    stop: Stop,
+) {
+    {
+        let mut servers = SERVERS.lock().unwrap_or_else(|err| err.into_inner());
+        servers.retain(|(server, _)| server.strong_count() > 0);
+        servers.push((Arc::downgrade(tunnels), snapshot));
+    }
+    if !sample {
+        return;
+    }
+
+    let alpha = 1.0 - (-SAMPLE_INTERVAL.as_secs_f64() / WINDOW_SECS).exp();
+    let mut stop = stop;
+    loop {
+        let tick = task::sleep(SAMPLE_INTERVAL);
+        futures::pin_mut!(tick);
+        if let Either::Right(_) = future::select(tick, &mut stop).await {
+            return;
+        }
+
+        let live = tunnels
+            .live
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for stats in live {
+            if stats
+                .established
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+                .is_none()
+            {
+                continue;
+            }
+            let (up, down) = (
+                stats.up.load(Ordering::Relaxed),
+                stats.down.load(Ordering::Relaxed),
+            );
+            let mut rates = stats.rates.lock().unwrap_or_else(|err| err.into_inner());
+            let secs = SAMPLE_INTERVAL.as_secs_f64();
+            rates.up += alpha * ((up - rates.last_up) as f64 / secs - rates.up);
+            rates.down += alpha * ((down - rates.last_down) as f64 / secs - rates.down);
+            rates.last_up = up;
+            rates.last_down = down;
+        }
+    }
+}
+
+// Every server still running, with its snapshot path
+fn servers() -> Vec<(Arc<Tunnels>, Option<String>)> {
+    SERVERS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .iter()
+        .filter_map(|(server, path)| Some((server.upgrade()?, path.clone())))
+        .collect()
+}
+
+// For SIGUSR1, each server's listing in turn
+pub(crate) fn log_listing() {
+    for (tunnels, _) in servers() {
+        tunnels.log_listing();
+    }
+}
+
+// For SIGUSR2, every server with a `--snapshot` file writes it
+pub(crate) fn write_snapshot() {
+    let mut written = false;
+    for (tunnels, path) in servers() {
+        if let Some(path) = path {
+            tunnels.write_snapshot(&path);
+            written = true;
+        }
+    }
+    if !written {
+        crate::log::warn("no --snapshot path to write the snapshot to");
+    }
+}
+
+impl Tunnels {
+    // Logs every open tunnel, busiest first
+    fn log_listing(&self) {
+        let mut tunnels = self
+            .live
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .map(|(id, stats)| (*id, stats.clone()))
+            .filter(|(_, stats)| {
+                stats
+                    .established
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .is_some()
+            })
+            .collect::<Vec<_>>();
+        let rate = |stats: &TunnelStats| {
+            let rates = stats.rates.lock().unwrap_or_else(|err| err.into_inner());
+            (rates.up, rates.down)
+        };
+        tunnels.sort_by(|(_, a), (_, b)| {
+            let (a, b) = (rate(a), rate(b));
+            (b.0 + b.1).total_cmp(&(a.0 + a.1))
+        });
+
+        crate::log::info(format_args!("{} open tunnels", tunnels.len()));
+        for (id, stats) in tunnels {
+            let (up, down) = rate(&stats);
+            let target = match &*stats
+                .established
+                .lock()
+                .unwrap_or_else(|err| err.into_inner())
+            {
+                Some(established) => established.target.clone(),
+                None => continue,
+            };
+            crate::log::info(format_args!(
+                "#{} {} -> {}: up {:.0} B/s, down {:.0} B/s, {} bytes sent, {} bytes received in {}s",
+                id,
+                stats.client,
+                target,
+                up,
+                down,
+                stats.up.load(Ordering::Relaxed),
+                stats.down.load(Ordering::Relaxed),
+                stats.started.elapsed().as_secs()
+            ));
+        }
+    }
+
+    // Writes every live connection to the `--snapshot` file as JSON, all read under one lock so
+    // they're from the same moment; the file is swapped in whole, so a reader never sees half of it
+    fn write_snapshot(&self, path: &str) {
+        let entries = self
+            .live
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .iter()
+            .map(|(id, stats)| snapshot_entry(*id, stats))
+            .collect::<Vec<_>>();
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let json = format!(
+            "{{\"time\":{},\"connections\":[{}]}}\n",
+            time,
+            entries.join(",")
+        );
+
+        let tmp = format!("{}.tmp", path);
+        let res = std::fs::write(&tmp, json).and_then(|_| std::fs::rename(&tmp, path));
+        match res {
+            Ok(()) => crate::log::info(format_args!(
+                "wrote {} connections to {}",
+                entries.len(),
+                path
+            )),
+            Err(err) => crate::log::err(format_args!("writing snapshot to {}: {}", path, err)),
+        }
+    }
+}
+
+fn snapshot_entry(id: u64, stats: &TunnelStats) -> String {
+    let quoted = |s: &str| format!("\"{}\"", crate::log::escape(s));
+    let (state, user, target) = match &*stats
+        .established
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+    {
+        Some(established) => (
+            "relay",
+            established
+                .user
+                .as_deref()
+                .map_or("null".to_string(), quoted),
+            quoted(&established.target),
+        ),
+        None => ("handshake", "null".to_string(), "null".to_string()),
+    };
+    format!(
+        "{{\"id\":{},\"client\":{},\"user\":{},\"target\":{},\"state\":\"{}\",\"bytes_sent\":{},\"bytes_received\":{},\"age_secs\":{}}}",
+        id,
+        quoted(&stats.client),
+        user,
+        target,
+        state,
+        stats.up.load(Ordering::Relaxed),
+        stats.down.load(Ordering::Relaxed),
+        stats.started.elapsed().as_secs()
+    )
+}
@@ -0,0 +1,160 @@
+use crate::{
+    address::Address,
+    config::Config,
+    ioutil::Stream,
+    protocol::{TYP_IPV4, TYP_IPV6},
+};
+use async_std::{
+    net::{IpAddr, SocketAddr, UdpSocket},
+    prelude::*,
+};
+use futures::future::{self, Either};
+use std::cell::Cell;
+
+pub(crate) async fn bind(local: Option<SocketAddr>) -> std::io::Result<UdpSocket> {
+    UdpSocket::bind(SocketAddr::new(crate::ioutil::listen_ip(local), 0)).await
+}
+
+// Relays datagrams for as long as the controlling TCP connection stays open,
+// returning the payload bytes sent and received like a TCP relay
+pub(crate) async fn relay<S: Stream>(
+    mut control: S,
+    socket: UdpSocket,
+    peer: Option<IpAddr>,
+    config: &Config,
+) -> Result<(u64, u64), std::io::Error> {
+    let sent = Cell::new(0);
+    let received = Cell::new(0);
+
+    let watch = async {
+        let mut buf = [0u8; 64];
+        while control.read(&mut buf).await? != 0 {}
+        Ok::<(), std::io::Error>(())
+    };
+    let forward = forward(&socket, peer, config, &sent, &received);
+    futures::pin_mut!(watch, forward);
+
+    match future::select(watch, forward).await {
+        Either::Left((res, _)) | Either::Right((res, _)) => res?,
+    }
+    Ok((sent.get(), received.get()))
+}
+
+async fn forward(
+    socket: &UdpSocket,
+    peer: Option<IpAddr>,
+    config: &Config,
+    sent: &Cell<u64>,
+    received: &Cell<u64>,
+) -> Result<(), std::io::Error> {
+    let mut buf = vec![0u8; 0xffff];
+    // The first datagram from the controlling client's address pins its source port
+    let mut client: Option<SocketAddr> = None;
+
+    loop {
+        let (n, from) = socket.recv_from(&mut buf).await?;
+
+        let from_client = match client {
+            Some(client) => client == from,
+            None => peer.is_none() || peer == Some(from.ip()),
+        };
+
+        if from_client {
+            client = Some(from);
+            let (target, payload) = match decode(&buf[..n]) {
+                Some(datagram) => datagram,
+                None => continue,
+            };
+            let mut addrs = match crate::server::socks5_resolve(&target, config).await {
+                Ok(addrs) => addrs,
+                Err(_) => continue,
+            };
+            if let Some(policy) = &config.exit_policy {
+                addrs.retain(|addr| policy.allows(addr));
+            }
+            if let Some(addr) = addrs.first() {
+                // Like UDP itself, a datagram that can't be sent is just dropped
+                if socket.send_to(payload, addr).await.is_ok() {
+                    sent.set(sent.get() + payload.len() as u64);
+                }
+            }
+        } else if let Some(client) = client {
+            let datagram = encode(from, &buf[..n]);
+            if socket.send_to(&datagram, client).await.is_ok() {
+                received.set(received.get() + n as u64);
+            }
+        }
+    }
+}
+
+// Splits a client datagram into its destination and payload, None if it's malformed or fragmented
+fn decode(datagram: &[u8]) -> Option<(Address, &[u8])> {
+    let header = datagram.get(..3)?;
+    if header[2] != 0 {
+        return None;
+    }
+    crate::protocol::decode_address(&datagram[3..])
+}
+
+fn encode(from: SocketAddr, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0x0, 0x0, 0x0];
+    match from.ip() {
+        IpAddr::V4(ip) => {
+            datagram.push(TYP_IPV4);
+            datagram.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            datagram.push(TYP_IPV6);
+            datagram.extend_from_slice(&ip.octets());
+        }
+    }
+    datagram.extend_from_slice(&from.port().to_be_bytes());
+    datagram.extend_from_slice(payload);
+    datagram
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::TYP_DOMAIN;
+
+    #[test]
+    fn header_round_trip() {
+        let from: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        let datagram = encode(from, b"query");
+        assert_eq!(datagram[..10], [0, 0, 0, TYP_IPV4, 192, 0, 2, 1, 0, 53]);
+        assert_eq!(decode(&datagram), Some((Address::Ip(from), &b"query"[..])));
+
+        let from: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let datagram = encode(from, b"");
+        assert_eq!(datagram.len(), 3 + 1 + 16 + 2);
+        assert_eq!(datagram[3], TYP_IPV6);
+        assert_eq!(decode(&datagram), Some((Address::Ip(from), &b""[..])));
+    }
+
+    #[test]
+    fn decodes_domains() {
+        let mut datagram = vec![0, 0, 0, TYP_DOMAIN, 11];
+        datagram.extend_from_slice(b"example.com\x00\x35payload");
+        assert_eq!(
+            decode(&datagram),
+            Some((
+                Address::Domain("example.com".to_string(), 53),
+                &b"payload"[..]
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_fragments_and_junk() {
+        let mut datagram = encode("192.0.2.1:53".parse().unwrap(), b"query");
+        datagram[2] = 1;
+        assert_eq!(decode(&datagram), None);
+
+        assert_eq!(decode(&[0, 0]), None);
+        assert_eq!(decode(&[0, 0, 0]), None);
+        assert_eq!(decode(&[0, 0, 0, TYP_IPV4, 192, 0, 2]), None);
+        assert_eq!(decode(&[0, 0, 0, TYP_DOMAIN, 11, b'e']), None);
+        assert_eq!(decode(&[0, 0, 0, 0x9, 192, 0, 2, 1, 0, 53]), None);
+    }
+}
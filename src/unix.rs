@@ -0,0 +1,64 @@
+use crate::config::Config;
+use async_std::{
+    io,
+    os::unix::net::{UnixListener, UnixStream},
+};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{SocketAddr, UnixListener as StdUnixListener};
+
+// A leading `@` selects the abstract namespace, which needs no socket file cleanup
+pub(crate) fn bind(name: &str) -> io::Result<UnixListener> {
+    let addr = match name.strip_prefix('@') {
+        Some(abstract_name) => SocketAddr::from_abstract_name(abstract_name)?,
+        None => SocketAddr::from_pathname(name)?,
+    };
+
+    Ok(UnixListener::from(StdUnixListener::bind_addr(&addr)?))
+}
+
+// Abstract sockets have no filesystem permissions to lean on, so an empty
+// allowlist means anyone on the host may connect
+pub(crate) fn peer_allowed(stream: &UnixStream, config: &Config) -> bool {
+    if config.allow_uids.is_empty() && config.allow_gids.is_empty() {
+        return true;
+    }
+
+    match peer_cred(stream) {
+        Ok(cred) => config.allow_uids.contains(&cred.uid) || config.allow_gids.contains(&cred.gid),
+        Err(_) => false,
+    }
+}
+
+// Unix clients are usually unnamed, so identify them by their credentials instead
+pub(crate) fn peer_name(stream: &UnixStream) -> String {
+    match peer_cred(stream) {
+        Ok(cred) => format!("unix pid={} uid={}", cred.pid, cred.uid),
+        Err(_) => "unix".to_string(),
+    }
+}
+
+fn peer_cred(stream: &UnixStream) -> io::Result<libc::ucred> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut libc::ucred as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    if ret == 0 {
+        Ok(cred)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{
+    address::Address,
+    config::{Config, UpstreamProtocol},
+    errors::Socks5Error,
+};
+use async_std::net::TcpStream;
+
+// How many proxies a tunnel may already have passed through before it's taken for a loop
+// between chained proxies. With --upstream-hop-tag each one tags the username it sends its
+// upstream with the count, after a NUL no real username has.
+pub(crate) const MAX_HOPS: u32 = 8;
+const HOP_TAG: char = '\0';
+// The NUL and a single digit, as the count never gets past MAX_HOPS
+pub(crate) const HOP_TAG_LEN: usize = 2;
+
+// Splits the hop count tag off a username a downstream proxy sent
+pub(crate) fn split_hops(username: String) -> (String, u32) {
+    let hops = username
+        .rsplit_once(HOP_TAG)
+        .and_then(|(user, hops)| Some((user.len(), hops.parse().ok()?)));
+    match hops {
+        Some((len, hops)) => {
+            let mut username = username;
+            username.truncate(len);
+            (username, hops)
+        }
+        None => (username, 0),
+    }
+}
+
+// Opens the tunnel through the `--upstream` proxy instead of connecting directly, along
+// with the BND a SOCKS5 upstream replied with
+pub(crate) async fn connect(
+    target: &Address,
+    hops: u32,
+    config: &Config,
+) -> Result<(TcpStream, Option<Address>), Socks5Error> {
+    if config.upstream_protocol == UpstreamProtocol::Http {
+        let mut stream = open(config).await?;
+        let auth = config
+            .upstream_user
+            .as_ref()
+            .map(|(user, pass)| (user.as_str(), pass.as_str()));
+        crate::http::connect(&mut stream, target, auth).await?;
+        return Ok((stream, None));
+    }
+    let (stream, bnd) = command(crate::protocol::CMD_CONNECT, target, hops, config).await?;
+    Ok((stream, Some(bnd)))
+}
+
+async fn open(config: &Config) -> Result<TcpStream, Socks5Error> {
+    match config.upstream {
+        Some(upstream) => Ok(crate::outbound::connect(&[upstream], config).await?),
+        None => Err(Socks5Error::InvalidConfig("no upstream".to_string())),
+    }
+}
+
+// Hands a RESOLVE or RESOLVE_PTR on to the SOCKS5 upstream, for `--dns-mode remote`
+pub(crate) async fn lookup(
+    cmd: u8,
+    target: &Address,
+    hops: u32,
+    config: &Config,
+) -> Result<Address, Socks5Error> {
+    let (_, answer) = command(cmd, target, hops, config).await?;
+    Ok(answer)
+}
+
+// `hops` is how many proxies the client's connection came through to get here
+async fn command(
+    cmd: u8,
+    target: &Address,
+    hops: u32,
+    config: &Config,
+) -> Result<(TcpStream, Address), Socks5Error> {
+    if config.upstream_hop_tag && hops >= MAX_HOPS {
+        crate::log::warn(format_args!(
+            "not chaining to {} after {} hops, the upstreams loop back",
+            config
+                .upstream
+                .map(|addr| addr.to_string())
+                .unwrap_or_default(),
+            hops
+        ));
+        return Err(Socks5Error::ConnectionNotAllowed);
+    }
+    let mut stream = open(config).await?;
+
+    let auth = config.upstream_user.as_ref().map(|(user, pass)| {
+        let user = match config.upstream_hop_tag {
+            true => format!("{}{}{}", user, HOP_TAG, hops + 1),
+            false => user.clone(),
+        };
+        (user, pass)
+    });
+    let auth = auth
+        .as_ref()
+        .map(|(user, pass)| (user.as_str(), pass.as_str()));
+    let bnd = crate::client::command(&mut stream, cmd, target, auth).await?;
+    Ok((stream, bnd))
+}